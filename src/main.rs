@@ -1,14 +1,24 @@
-use clap::{command, Parser};
+use clap::Parser;
 use once_cell::sync::Lazy;
 use toml::Table;
-use std::{fs::{self, DirEntry}, sync::{RwLock, Mutex}};
+use std::{collections::{hash_map::RandomState, HashMap, HashSet, VecDeque}, fs::{self, DirEntry}, path::{Path, PathBuf}, hash::{BuildHasher, Hasher}, io::{BufRead, Read, Write}, process::{Command, Stdio}, os::unix::{fs::{FileTypeExt, MetadataExt}, io::AsRawFd, net::UnixListener}, sync::{atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}, RwLock, Mutex}, time::{Duration, Instant, SystemTime}};
 use sysinfo::{MemoryRefreshKind, RefreshKind, System};
 use regex::{Regex, RegexBuilder};
-use memmap2::Mmap;
+use memmap2::{Advice, Mmap, MmapOptions};
 use rayon::prelude::*;
 
 #[derive(PartialEq)]
-enum SortingMethod { FL, SL, LS }
+enum SortingMethod { FL, SL, LS, ShallowFirst, Trace }
+#[derive(PartialEq, Clone, Copy)]
+enum FileType { Elf, Exec, So }
+#[derive(Clone, Copy)]
+enum MemPolicy { Interleave, Local, Preferred(u32) }
+#[derive(Clone, Copy)]
+enum WarnThreshold { Fraction(f64), Size(usize) }
+#[derive(PartialEq, Clone, Copy)]
+enum MemlockLimitPolicy { Warn, Truncate, Error }
+#[derive(PartialEq, Clone, Copy)]
+enum BudgetPolicy { Skip, Stop }
 const KIB: usize = 1024;
 const MIB: usize = 1048576;
 const GIB: usize = 1073741824;
@@ -17,63 +27,496 @@ const GIB: usize = 1073741824;
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
-    /// Custom configuration path
+    /// Custom configuration path, defaults to /etc/prelockd-rs.toml unless set when building
     #[arg(short, long, required = false)]
     config: Option<String>,
-    /// Outputs memory usage for configuration; this will load files into memory to get accurate usage
+    /// Format of the configuration, toml or yaml, by default taken from its extension
+    #[arg(long, required = false)]
+    config_format: Option<String>,
+    /// Outputs memory usage for configuration once the files are locked, then keeps running unless --once is given
     #[arg(short, long, required = false)]
     usage: bool,
+    /// Group the --usage listing by the filesystem the files are on
+    #[arg(long, required = false, requires = "usage")]
+    by_fs: bool,
+    /// Also list groups of locked files with identical content and how much locking one copy would save
+    #[arg(long, required = false, requires = "usage")]
+    show_dupes: bool,
+    /// Exit after locking instead of running as a daemon, the files are unlocked again on exit
+    #[arg(long, required = false)]
+    once: bool,
+    /// Compares the locked memory reported by the kernel (VmLck) against what was accounted for, then exits
+    #[arg(long, required = false)]
+    verify_accounting: bool,
+    /// Reports how many pages of each file were already cached and how many had to be read in
+    #[arg(long, required = false)]
+    report_baseline: bool,
+    /// Print a line for every file as it is loaded: <locked|prewarmed> <size> <total> <path>
+    #[arg(long, required = false)]
+    events: bool,
+    /// Print every file matched by the load table, before sorting and the budget cut, then exit without locking anything
+    #[arg(long, required = false)]
+    list_candidates: bool,
+    /// Show which files fit the budget in the order they would be locked and where the cut falls, then exit without locking anything
+    #[arg(long, required = false)]
+    explain: bool,
+    /// Log every file as it gets locked with its size and the running total
+    #[arg(long, required = false)]
+    log_locked: bool,
+    /// Continue an interrupted start, files recorded in checkpoint_file are locked again without rescanning for them
+    #[arg(long, required = false)]
+    resume: bool,
+    /// On shutdown, save the candidate files that are in the page cache to this file
+    #[arg(long, required = false)]
+    save_residency: Option<String>,
+    /// Lock the files saved with --save-residency first, as far as the budget allows and if they still pass the filters of the config
+    #[arg(long, required = false)]
+    warm_from_residency: Option<String>,
+    /// Lock the files, print the locked paths for another tool and exit; vmtouch gives one path per line for vmtouch -l, the startup log goes to stderr
+    #[arg(long, required = false)]
+    export: Option<String>,
+    /// Wait this long before scanning, in seconds or with s, m or h, instead of startup_delay in the config
+    #[arg(long, required = false)]
+    startup_delay: Option<String>,
+    /// Once the files are locked, tell the instance answering on this status_socket to exit, for upgrades without an unlocked window
+    #[arg(long, required = false)]
+    take_over: Option<String>,
+    /// After the files are locked, write the outcome as a JSON object to this file, or to stderr for -
+    #[arg(long, required = false)]
+    summary_json: Option<String>,
+    /// Log every directory entry the scan comes across, its type, size and whether it passed the filters, also with --explain
+    #[arg(long, required = false)]
+    trace_scan: bool,
 }
 
 struct Lock {
     current_size: usize,
+    /// Mapped but not yet locked, still counts against max_total_size
+    prewarm_size: usize,
     max_file_size: usize,
     max_total_size: usize,
     memory_size: usize,
+    min_stable_age: Option<Duration>,
+    lock_fraction: f64,
+    /// Drop the part of partially locked files past lock_fraction from the page cache
+    drop_unlocked_remainder: bool,
+    /// Count files against the budget by the whole pages they take
+    count_whole_pages: bool,
+    /// Count the memory of prelockd itself against the budget as well
+    count_overhead: bool,
+    /// Warn once locked memory reaches this, a fraction of max_total_size or a size
+    warn_threshold: Option<WarnThreshold>,
+    /// Whether selection goes through every file or ends once none of the rest can fit
+    budget_policy: BudgetPolicy,
+    dedup_hardlinks: bool,
+    upgrade_locks: bool,
+    /// Read all files into the page cache before locking any of them
+    two_phase: bool,
+    /// Threads for the readahead phase of two_phase, 0 for one per CPU
+    prefetch_concurrency: usize,
+    /// Readahead is asked for in chunks of this many bytes when prewarming, whole pages, 0 for the whole file at once
+    readahead_bytes: usize,
+    /// NUMA policy of the threads while they read in and lock files
+    mempolicy: Option<MemPolicy>,
+    /// Largest single mapping, bigger files are split, 0 for no limit
+    max_mapping_size: usize,
+    /// Fraction of vm.max_map_count this process may take up with mappings
+    max_map_fraction: f64,
+    /// Raise vm.max_map_count instead of locking fewer files, when privileged
+    raise_max_map_count: bool,
+    /// What to do when the selection doesn't fit in RLIMIT_MEMLOCK
+    memlock_limit_policy: MemlockLimitPolicy,
+    /// Prewarm instead of failing when mlock isn't permitted at all
+    prewarm_fallback: bool,
+    refuse_world_writable: bool,
+    /// Only files owned by this user and group are candidates
+    owner_uid: Option<u32>,
+    owner_gid: Option<u32>,
+    /// Only executable or ELF files are candidates
+    executables_only: bool,
+    /// Fraction of the memory that fit in the budget that has to get locked
+    min_success_fraction: f64,
+    state_file: Option<String>,
+    /// State files of other instances on the host, to tell which files they already lock
+    peer_state_files: Vec<String>,
+    /// Leave files another instance locks to it instead of locking them as well
+    skip_peer_files: bool,
+    checkpoint_file: Option<String>,
+    status_socket: Option<String>,
+    /// Permission bits and group applied to status_socket once it is bound
+    status_socket_mode: Option<u32>,
+    status_socket_group: Option<u32>,
+    /// How long mlock took for the files of the startup pass
+    lock_latency: Option<Latency>,
+    /// Unlocked for min_available, in lock order, to be locked again once memory recovers
+    evicted: Vec<(String, FileInfo)>,
+    /// Files of the startup pass that weren't locked, by why
+    skipped: Vec<(&'static str, usize)>,
+    /// Files were left out of the startup pass for lack of budget
+    budget_limited: bool,
+    /// Time to wait before scanning so other services can start first
+    startup_delay: Option<Duration>,
+    /// Tell systemd the daemon is ready once this fraction of the selection is locked, instead of after all of it
+    ready_after_fraction: Option<f64>,
+    alert_webhook: Option<String>,
+    score_command: Option<String>,
+    score_timeout: Duration,
+    rescan_interval: Duration,
+    /// Each interval is randomly moved by up to this much
+    rescan_jitter: Duration,
+    residency_check_interval: Option<Duration>,
+    residency_retries: u32,
+    /// Unlock files when available memory falls below this
+    min_available: Option<usize>,
+    /// Until available memory is back above this
+    resume_available: usize,
+    /// Turns config warnings into errors
+    strict: bool,
+    locations: Vec<Location>,
+    /// Paths that have to be mount points before scanning
+    wait_for_mounts: Vec<String>,
+    mount_timeout: Duration,
+    /// Fail instead of scanning anyway when mount_timeout passes
+    mount_timeout_fail: bool,
+    scan_threads: usize,
+    /// Nice level of the scan threads, their IO priority follows it
+    scan_nice: Option<i32>,
+    /// max_total_size outside of scheduled windows
+    base_max_total_size: usize,
+    /// PRELOCKD_MAX_LOCK
+    max_lock_ceiling: Option<usize>,
+    /// PRELOCKD_ALLOWED_ROOTS, canonicalized
+    allowed_roots: Option<Vec<PathBuf>>,
+    schedule: Vec<Window>,
+    active_window: Option<usize>,
+    /// Kept to find files again when the schedule changes
+    config: Table,
+    config_file: String,
+    config_yaml: bool,
+    /// Take files from the lists in turn instead of one list after another
+    round_robin: bool,
+    /// Paths in the order they were first accessed, for the trace sorting method
+    access_trace: Option<String>,
+    /// Every scanned file is a candidate, except for the ones matching exclude
+    lock_all: bool,
+    /// Match patterns against the real path of scanned files, which is also the path they are loaded under
+    match_canonical: bool,
+    /// Files matching these are never loaded, wherever they were found
+    exclude: Vec<Regex>,
     sorting_method: SortingMethod
 }
 
+/// A time of day during which other lists and budget apply
+struct Window {
+    /// Minutes since midnight, local time
+    start: u32,
+    end: u32,
+    lists: Vec<String>,
+    max_total_size: Option<usize>
+}
+
+#[derive(Clone)]
 struct FileInfo {
-    size: u64
+    size: u64,
+    inode: (u64, u64),
+    aliases: Vec<String>,
+    track_growth: bool,
+    /// Index into locations, None for files found elsewhere
+    location: Option<usize>,
+    /// All_or_nothing list or coherent location the file belongs to
+    all_or_nothing: Option<String>,
+    /// Position of the list that matched the file, None for files not matched by a pattern
+    list: Option<usize>
+}
+
+impl FileInfo {
+    fn new(file_data: &fs::Metadata, lock: &Lock) -> FileInfo {
+        FileInfo { size: lock_length(file_data.len(), lock), inode: (file_data.dev(), file_data.ino()), aliases: Vec::new(), track_growth: false, location: None, all_or_nothing: None, list: None }
+    }
+}
+
+/// Settings of a list given as a table instead of a plain array of patterns
+#[derive(Clone, Default)]
+struct ListOptions {
+    /// Extend the locked mapping when the file grows
+    track_growth: bool,
+    keep_latest: Option<KeepLatest>,
+    /// Lock every file of the list or none of them
+    all_or_nothing: bool
+}
+
+/// Only the newest count versions of a file are locked,
+/// files are the same artifact when their names match with the version taken out
+#[derive(Clone)]
+struct KeepLatest {
+    count: usize,
+    version: Regex,
+    by_mtime: bool
+}
+
+/// Passed to the on_lock hook for every file as it gets mapped
+struct LockEvent<'a> {
+    path: &'a str,
+    size: usize,
+    /// Memory mapped so far, including this file
+    total: usize,
+    locked: bool,
+    /// Pages that were already in the page cache before loading
+    cached: usize,
+    pages: usize,
+    location: Option<usize>
+}
+
+#[derive(Default)]
+struct Location {
+    path: String,
+    /// Warn when a scan finds fewer files, the location is probably not mounted yet
+    min_files: usize,
+    max_total_size: Option<usize>,
+    /// Patterns and lists only matched against files from this location, on top of the ones in load
+    files: Vec<String>,
+    lists: Vec<String>,
+    /// Descend into subdirectories, at most max_depth levels
+    recursive: bool,
+    max_depth: Option<usize>,
+    /// Every file found here is a candidate, except for the ones matching exclude
+    lock_all: bool,
+    /// Files here matching these are never loaded
+    exclude: Vec<Regex>,
+    /// Files found by the last scan
+    scanned: AtomicUsize,
+    /// Files of the last scan matched by the load table
+    matched: AtomicUsize,
+    /// Lock the files from here as one set, and all of them again when any of them changes
+    coherent: bool,
+    /// The selected files of a coherent location when its set was last locked, by path
+    stamps: Vec<(String, Stamp)>
+}
+
+/// What tells a file apart from a changed version of it
+#[derive(PartialEq)]
+struct Stamp {
+    inode: (u64, u64),
+    size: u64,
+    mtime: (i64, i64)
+}
+
+/// Memory taken so far, in total and per location
+#[derive(Clone)]
+struct Budget {
+    total: usize,
+    locations: Vec<usize>,
+    /// Sizes are rounded up to this, the page size with count_whole_pages
+    unit: usize
+}
+
+impl Budget {
+    fn new(lock: &Lock) -> Budget {
+        // Own memory of the moment, it changes little once the files are found
+        let total = if lock.count_overhead { read_overhead().unwrap_or(0) } else { 0 };
+        Budget { total, locations: vec![0; lock.locations.len()], unit: if lock.count_whole_pages { page_size() } else { 1 } }
+    }
+
+    /// What the loaded files already take up
+    fn from_loaded(loaded: &[LoadedFile], lock: &Lock) -> Budget {
+        let mut budget = Budget::new(lock);
+        for file in loaded {
+            budget.add(file.location, file.mmap.len());
+        }
+        budget
+    }
+
+    fn add(&mut self, location: Option<usize>, size: usize) {
+        let size = size.div_ceil(self.unit)*self.unit;
+        self.total += size;
+        if let Some(location) = location {
+            self.locations[location] += size;
+        }
+    }
+
+    /// Takes size out of the budget if it fits both max_total_size and the cap of its location
+    fn try_reserve(&mut self, location: Option<usize>, size: usize, lock: &Lock) -> bool {
+        let size = size.div_ceil(self.unit)*self.unit;
+        if self.total + size > lock.max_total_size {
+            return false
+        }
+        if let Some(location) = location {
+            if let Some(cap) = lock.locations[location].max_total_size {
+                if self.locations[location] + size > cap {
+                    return false
+                }
+            }
+        }
+        self.add(location, size);
+        true
+    }
+
+    /// The files that get a share of the budget, in order. Files larger than what is left are skipped,
+    /// under budget_policy stop the rest aren't looked at once what is left is less than the smallest of them.
+    fn select<'a>(&mut self, to_load: &'a [(String, FileInfo)], lock: &Lock) -> Vec<&'a (String, FileInfo)> {
+        let mut smallest = vec![usize::MAX; to_load.len()+1];
+        if lock.budget_policy == BudgetPolicy::Stop {
+            for (index, file) in to_load.iter().enumerate().rev() {
+                smallest[index] = smallest[index+1].min((file.1.size as usize).div_ceil(self.unit)*self.unit);
+            }
+        }
+        let mut selected = Vec::new();
+        for (index, file) in to_load.iter().enumerate() {
+            let remaining = lock.max_total_size.saturating_sub(self.total);
+            if lock.budget_policy == BudgetPolicy::Stop && remaining < smallest[index] {
+                break;
+            }
+            if (file.1.size as usize).div_ceil(self.unit)*self.unit > remaining {
+                continue;
+            }
+            if self.try_reserve(file.1.location, file.1.size as usize, lock) {
+                selected.push(file);
+            }
+        }
+        selected
+    }
+
+    fn release(&mut self, location: Option<usize>, size: usize) {
+        let size = size.div_ceil(self.unit)*self.unit;
+        self.total -= size;
+        if let Some(location) = location {
+            self.locations[location] -= size;
+        }
+    }
+}
+
+/// A file mapped in one or more consecutive segments of at most max_mapping_size.
+/// Segments are always read-only Mmap (never MmapMut) of files opened read-only, so locked pages can't be dirtied.
+struct Mapping {
+    segments: Vec<Mmap>
+}
+
+impl Mapping {
+    fn len(&self) -> usize {
+        self.segments.iter().map(|segment| segment.len()).sum()
+    }
+
+    /// Locks every segment, or none of them
+    fn lock(&self) -> std::io::Result<()> {
+        for (index, segment) in self.segments.iter().enumerate() {
+            if let Err(err) = segment.lock() {
+                for segment in &self.segments[..index] {
+                    let _ = segment.unlock();
+                }
+                return Err(err)
+            }
+        }
+        Ok(())
+    }
+
+    /// Asks for readahead of the whole mapping, chunk bytes per madvise call, all of it in one when chunk is 0
+    fn prewarm(&self, chunk: usize) {
+        for segment in &self.segments {
+            if chunk == 0 {
+                let _ = segment.advise(Advice::WillNeed);
+                continue;
+            }
+            for offset in (0..segment.len()).step_by(chunk) {
+                let _ = segment.advise_range(Advice::WillNeed, offset, chunk.min(segment.len() - offset));
+            }
+        }
+    }
+
+    /// Memory taken up by the mapping, the kernel works in whole pages
+    fn page_aligned_len(&self, page_size: usize) -> usize {
+        self.segments.iter().map(|segment| segment.len().div_ceil(page_size)*page_size).sum()
+    }
+}
+
+struct LoadedFile {
+    path: String,
+    mmap: Mapping,
+    locked: bool,
+    /// Hardlinks sharing the mapping of path
+    aliases: Vec<String>,
+    track_growth: bool,
+    location: Option<usize>,
+    /// The all_or_nothing list or coherent location the file was locked with, evicted together with it
+    all_or_nothing: Option<String>,
+    /// Times in a row the file was found not resident and locked again
+    relock_attempts: u32
 }
 
-static mut LOADED: Lazy<RwLock<Vec<(String,Mmap)>>> = Lazy::new(|| {
+static LOADED: Lazy<RwLock<Vec<LoadedFile>>> = Lazy::new(|| {
     RwLock::new(Vec::new())
 });
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+static RELOAD: AtomicBool = AtomicBool::new(false);
+/// Inode of the last state file written, so one that a successor replaced isn't removed on exit
+static STATE_INODE: AtomicU64 = AtomicU64::new(0);
+/// Set by pause on the status socket, rescans, reloads and eviction wait until resume
+static PAUSED: AtomicBool = AtomicBool::new(false);
+/// Set by --trace-scan, every directory entry a scan comes across is logged with what became of it
+static TRACE_SCAN: AtomicBool = AtomicBool::new(false);
+/// A path from add-location on the status socket, with where to send the outcome
+type LocationRequest = (String, std::sync::mpsc::Sender<String>);
+/// Waiting for daemon_run to scan them
+static ADDED_LOCATIONS: Lazy<Mutex<Vec<LocationRequest>>> = Lazy::new(|| {
+    Mutex::new(Vec::new())
+});
+/// Files that couldn't be opened for lack of permission since the last summary
+static PERMISSION_DENIED: AtomicUsize = AtomicUsize::new(0);
+/// Files that couldn't be mapped or locked since the last alert, with the reason
+static FAILURES: Lazy<Mutex<Vec<(String, String)>>> = Lazy::new(|| {
+    Mutex::new(Vec::new())
+});
+/// How long each successful mlock took during the startup pass
+static LOCK_LATENCIES: Lazy<Mutex<Vec<Duration>>> = Lazy::new(|| {
+    Mutex::new(Vec::new())
+});
+const STATE_VERSION: u32 = 1;
+/// Packagers can pin the only roots files may be locked from with PRELOCKD_ALLOWED_ROOTS at build time, separated by :
+const ALLOWED_ROOTS: Option<&str> = option_env!("PRELOCKD_ALLOWED_ROOTS");
+/// Packagers can move the default config with PRELOCKD_DEFAULT_CONFIG at build time
+const DEFAULT_CONFIG: &str = match option_env!("PRELOCKD_DEFAULT_CONFIG") {
+    Some(v) => v,
+    None => "/etc/prelockd-rs.toml"
+};
 
-fn size_to_bytes(size: &str, lock: &Lock) -> Option<usize> {
-    static STB_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"(\d*)([m,k,g,%])?").unwrap());
-    if let Some(data) = STB_RE.captures(size) {
-        if let Some(value) = data.get(1) {
-            let value = value.as_str().parse::<usize>().unwrap_or(0);
-            if value != 0 {
-                if let Some(qualifier) = data.get(2) {
-                    match qualifier.as_str() {
-                        "k" => {
-                            return Some(value*KIB)
-                        }
-                        "m" => {
-                            return Some(value*MIB)
-                        }
-                        "g" => {
-                            return Some(value*GIB)
-                        }
-                        "%" => {
-                            return Some(((lock.memory_size*value) as f64*0.01) as usize)
-                        }
-                        _ => {
-                            return Some(value)
-                        }
+fn size_to_bytes(size: &str, memory_size: usize) -> Option<usize> {
+    // min(2g, 20%) and max(...), taking any sizes including other min and max
+    let trimmed = size.trim().to_lowercase();
+    for (name, pick) in [("min(", usize::min as fn(usize, usize) -> usize), ("max(", usize::max)] {
+        if let Some(inner) = trimmed.strip_prefix(name).and_then(|v| v.strip_suffix(')')) {
+            let mut sizes = Vec::new();
+            let (mut depth, mut start) = (0, 0);
+            for (index, c) in inner.char_indices() {
+                match c {
+                    '(' => depth += 1,
+                    ')' => depth -= 1,
+                    ',' if depth == 0 => {
+                        sizes.push(&inner[start..index]);
+                        start = index+1;
                     }
-                } else {
-                    return Some(value)
+                    _ => {}
                 }
-            } else {
-                return Some(0)
             }
+            sizes.push(&inner[start..]);
+            return sizes.into_iter().map(|size| size_to_bytes(size.trim(), memory_size)).reduce(|a, b| Some(pick(a?, b?)))?
         }
     }
-    None
+    // Named fractions of system memory
+    match trimmed.as_str() {
+        "half" => return Some(memory_size/2),
+        "third" => return Some(memory_size/3),
+        "quarter" => return Some(memory_size/4),
+        _ => {}
+    }
+    static STB_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\A(\d+)([kmg%])?\z").unwrap());
+    let data = STB_RE.captures(&trimmed)?;
+    let value = data[1].parse::<usize>().ok()?;
+    match data.get(2).map(|v| v.as_str()) {
+        Some("k") => value.checked_mul(KIB),
+        Some("m") => value.checked_mul(MIB),
+        Some("g") => value.checked_mul(GIB),
+        Some("%") => Some((memory_size as u128*value as u128/100) as usize),
+        _ => Some(value)
+    }
 }
 
 
@@ -89,59 +532,597 @@ fn bytes_to_size(num: usize) -> String {
     }
 }
 
-fn daemon_setup(config_file: &str) -> Result<(), String> {
+fn time_to_duration(time: &str) -> Option<Duration> {
+    static TTD_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\A(\d+)([s,m,h,d])?\z").unwrap());
+    let data = TTD_RE.captures(time)?;
+    let value = data.get(1)?.as_str().parse::<u64>().ok()?;
+    match data.get(2).map(|v| v.as_str()) {
+        Some("m") => Some(Duration::from_secs(value*60)),
+        Some("h") => Some(Duration::from_secs(value*3600)),
+        Some("d") => Some(Duration::from_secs(value*86400)),
+        _ => Some(Duration::from_secs(value))
+    }
+}
+
+/// Accepts either a float (0.25) or a percentage string ("25%")
+fn fraction_from_value(value: &toml::Value) -> Option<f64> {
+    match value {
+        toml::Value::Float(v) => Some(*v),
+        toml::Value::Integer(v) => Some(*v as f64),
+        toml::Value::String(v) => {
+            if let Some(v) = v.strip_suffix('%') {
+                v.trim().parse::<f64>().ok().map(|v| v*0.01)
+            } else {
+                v.trim().parse::<f64>().ok()
+            }
+        }
+        _ => None
+    }
+}
+
+/// Amount of a file that gets locked, the leading lock_fraction of it
+fn lock_length(size: u64, lock: &Lock) -> u64 {
+    if lock.lock_fraction >= 1.0 {
+        size
+    } else {
+        (size as f64*lock.lock_fraction).ceil() as u64
+    }
+}
+
+/// Collapses paths pointing at the same inode into one entry, keeping the rest as aliases
+fn dedup_hardlinks(to_load: &mut Vec<(String, FileInfo)>) {
+    let mut seen: HashMap<(u64, u64), usize> = HashMap::new();
+    let mut deduped: Vec<(String, FileInfo)> = Vec::with_capacity(to_load.len());
+    for (path, info) in to_load.drain(..) {
+        if let Some(&index) = seen.get(&info.inode) {
+            let entry = &mut deduped[index];
+            if entry.0 != path && !entry.1.aliases.contains(&path) {
+                entry.1.aliases.push(path);
+            }
+        } else {
+            seen.insert(info.inode, deduped.len());
+            deduped.push((path, info));
+        }
+    }
+    *to_load = deduped;
+}
+
+/// Classifies a file by its leading bytes, only ELF files are recognized
+fn detect_file_type(path: &str) -> Option<FileType> {
+    let mut header = [0u8; 18];
+    fs::File::open(path).ok()?.read_exact(&mut header).ok()?;
+    if &header[0..4] != b"\x7fELF" {
+        return None
+    }
+    // e_type follows the 16 byte e_ident, in the byte order given by EI_DATA
+    let e_type = match header[5] {
+        2 => u16::from_be_bytes([header[16], header[17]]),
+        _ => u16::from_le_bytes([header[16], header[17]])
+    };
+    match e_type {
+        2 => Some(FileType::Exec),
+        3 => Some(FileType::So),
+        _ => Some(FileType::Elf)
+    }
+}
+
+/// Files modified more recently than min_stable_age are still changing and not worth locking
+fn is_stable(file_data: &fs::Metadata, min_stable_age: Option<Duration>) -> bool {
+    if let Some(min_stable_age) = min_stable_age {
+        if let Ok(modified) = file_data.modified() {
+            return SystemTime::now().duration_since(modified).unwrap_or(Duration::ZERO) >= min_stable_age
+        }
+    }
+    true
+}
+
+/// Checks a file against the size and file filters from the lock table
+fn is_candidate(path: &Path, file_data: &fs::Metadata, lock: &Lock) -> bool {
+    if !file_data.is_file() || lock_length(file_data.len(), lock) as usize > lock.max_file_size || !is_stable(file_data, lock.min_stable_age) {
+        return false
+    }
+    if lock.refuse_world_writable && file_data.mode() & 0o002 != 0 {
+        println!("Skipping world writable {}", path.display());
+        return false
+    }
+    if lock.owner_uid.is_some_and(|uid| file_data.uid() != uid) || lock.owner_gid.is_some_and(|gid| file_data.gid() != gid) {
+        return false
+    }
+    // Shared libraries often aren't executable, the magic is only read for files without the bit
+    !lock.executables_only || file_data.mode() & 0o111 != 0 || path.to_str().and_then(detect_file_type).is_some()
+}
+
+/// Regular files a process has open, found through /proc/<pid>/fd
+fn scan_pid_fds(pid: i64, lock: &Lock) -> Vec<(String, FileInfo)> {
+    let mut files = Vec::new();
+    let fd_dir = format!("/proc/{}/fd", pid);
+    let Ok(entries) = fs::read_dir(&fd_dir) else {
+        println!("Couldn't read {}", fd_dir);
+        return files
+    };
+    for fd in entries.flatten() {
+        let Ok(target) = fs::read_link(fd.path()) else {
+            continue
+        };
+        // Sockets, pipes and deleted files don't resolve to a usable path
+        if !target.is_absolute() {
+            continue;
+        }
+        if let (Some(path), Ok(file_data)) = (target.to_str(), fs::metadata(&target)) {
+            if is_candidate(&target, &file_data, lock) {
+                files.push((String::from(path), FileInfo::new(&file_data, lock)));
+            }
+        }
+    }
+    files
+}
+
+/// Sets nice and the best-effort IO priority of the calling thread, Linux applies both per thread
+fn set_thread_priority(nice: i32) {
+    const IOPRIO_CLASS_BE: libc::c_int = 2;
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    unsafe {
+        let tid = libc::gettid();
+        if libc::setpriority(libc::PRIO_PROCESS, tid as libc::id_t, nice) != 0 {
+            println!("Failed to set the nice level of a scan thread to {}: {}", nice, std::io::Error::last_os_error());
+        }
+        // The same mapping the kernel uses when no IO priority is set, 0 is the highest of 8 levels
+        let level = (nice+20)/5;
+        if libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, tid, (IOPRIO_CLASS_BE << 13) | level) != 0 {
+            println!("Failed to set the IO priority of a scan thread: {}", std::io::Error::last_os_error());
+        }
+    }
+}
+
+/// Whether a canonical path is under one of the allowed roots, everything is without them
+fn is_allowed(path: &Path, lock: &Lock) -> bool {
+    lock.allowed_roots.as_ref().is_none_or(|roots| roots.iter().any(|root| path.starts_with(root)))
+}
+
+/// Builds exclude patterns, matched against the end of the path like the patterns in load
+fn exclude_patterns(patterns: &[String]) -> Result<Vec<Regex>, String> {
+    patterns.iter().map(|pattern| Regex::new(&format!(r"/{}\z", pattern)).map_err(|err| format!("{}: {}", pattern, err))).collect()
+}
+
+fn is_excluded(path: &str, lock: &Lock, location: Option<usize>) -> bool {
+    lock.exclude.iter().chain(location.iter().flat_map(|location| lock.locations[*location].exclude.iter())).any(|re| re.is_match(path))
+}
+
+fn trace_entry(path: &Path, file_data: &fs::Metadata, candidate: bool, lock: &Lock) {
+    let file_type = file_data.file_type();
+    let kind = if file_type.is_file() {
+        "file"
+    } else if file_type.is_dir() {
+        "dir"
+    } else if file_type.is_symlink() {
+        "symlink"
+    } else {
+        "special"
+    };
+    let verdict = if candidate {
+        "candidate"
+    } else if !file_type.is_file() {
+        "not a regular file"
+    } else if lock_length(file_data.len(), lock) as usize > lock.max_file_size {
+        "over max_file_size"
+    } else {
+        "filtered"
+    };
+    println!("Scan {} - {} {}, {}", path.display(), kind, bytes_to_size(file_data.len() as usize), verdict);
+}
+
+fn scan_location(location: &Location, lock: &Lock) -> Vec<DirEntry> {
+    let mut files = Vec::new();
+    if let Ok(location_data) = fs::metadata(&location.path) {
+        if location_data.is_dir() {
+            let mut dirs = vec![(PathBuf::from(&location.path), 0)];
+            while let Some((dir, depth)) = dirs.pop() {
+                let entries = match fs::read_dir(&dir) {
+                    Ok(v) => v,
+                    Err(err) => {
+                        println!("Couldn't read {}: {}", dir.display(), err);
+                        continue
+                    }
+                };
+                let trace = TRACE_SCAN.load(Ordering::Relaxed);
+                for file in entries.flatten() {
+                    // file_type doesn't follow symlinks, so symlinked directories can't lead into a cycle
+                    if location.recursive && file.file_type().is_ok_and(|v| v.is_dir()) {
+                        let descend = location.max_depth.is_none_or(|max_depth| depth < max_depth);
+                        if trace {
+                            println!("Scan {} - dir, {}", file.path().display(), if descend { "descending" } else { "past max_depth" });
+                        }
+                        if descend {
+                            dirs.push((file.path(), depth+1));
+                        }
+                        continue;
+                    }
+                    match file.metadata() {
+                        Ok(file_data) => {
+                            let candidate = is_candidate(&file.path(), &file_data, lock);
+                            if trace {
+                                trace_entry(&file.path(), &file_data, candidate, lock);
+                            }
+                            if candidate {
+                                files.push(file);
+                            }
+                        }
+                        Err(err) if trace => println!("Scan {} - no metadata: {}", file.path().display(), err),
+                        Err(_) => {}
+                    }
+                }
+            }
+        }
+    }
+    files
+}
+
+/// Parses the config into a Lock without touching any files
+fn read_config(config_file: &str, yaml: bool) -> Result<Lock, String> {
     let config_data = match fs::read_to_string(config_file) {
         Ok(v) => v,
         Err(err) => return Err(format!("Failed reading {}: {}", config_file, err))
     };
-    let config = match toml::from_str::<Table>(&config_data) {
+    let config = if yaml { parse_yaml(&config_data) } else { toml::from_str::<Table>(&config_data).map_err(|err| err.to_string()) };
+    let mut config = match config {
         Ok(v) => v,
         Err(err) => return Err(format!("Failed reading {}: {}", config_file, err))
     };
     
-    let sys = System::new_with_specifics(RefreshKind::new().with_memory(MemoryRefreshKind::new().with_ram()));
     let mut lock: Lock = Lock {
         current_size: 0,
+        prewarm_size: 0,
         max_file_size: 20*MIB,
-        memory_size: sys.total_memory() as usize,
+        memory_size: total_memory()?,
         max_total_size: 0,
+        min_stable_age: None,
+        lock_fraction: 1.0,
+        drop_unlocked_remainder: false,
+        count_whole_pages: false,
+        count_overhead: false,
+        warn_threshold: None,
+        budget_policy: BudgetPolicy::Skip,
+        dedup_hardlinks: false,
+        upgrade_locks: false,
+        two_phase: false,
+        prefetch_concurrency: 0,
+        readahead_bytes: 2*MIB,
+        mempolicy: None,
+        max_mapping_size: 0,
+        max_map_fraction: 0.9,
+        raise_max_map_count: false,
+        memlock_limit_policy: MemlockLimitPolicy::Warn,
+        prewarm_fallback: false,
+        refuse_world_writable: false,
+        owner_uid: None,
+        owner_gid: None,
+        executables_only: false,
+        min_success_fraction: 0.0,
+        state_file: None,
+        peer_state_files: Vec::new(),
+        skip_peer_files: false,
+        checkpoint_file: None,
+        status_socket: None,
+        status_socket_mode: None,
+        status_socket_group: None,
+        lock_latency: None,
+        evicted: Vec::new(),
+        skipped: Vec::new(),
+        budget_limited: false,
+        startup_delay: None,
+        ready_after_fraction: None,
+        alert_webhook: None,
+        score_command: None,
+        score_timeout: Duration::from_secs(10),
+        rescan_interval: Duration::from_secs(30),
+        rescan_jitter: Duration::from_secs(3),
+        residency_check_interval: None,
+        residency_retries: 3,
+        min_available: None,
+        resume_available: 0,
+        strict: false,
+        locations: Vec::new(),
+        wait_for_mounts: Vec::new(),
+        mount_timeout: Duration::from_secs(120),
+        mount_timeout_fail: false,
+        scan_threads: 0,
+        scan_nice: None,
+        base_max_total_size: 0,
+        max_lock_ceiling: None,
+        allowed_roots: None,
+        schedule: Vec::new(),
+        active_window: None,
+        config: Table::new(),
+        config_file: String::from(config_file),
+        config_yaml: yaml,
+        round_robin: false,
+        access_trace: None,
+        lock_all: false,
+        match_canonical: false,
+        exclude: Vec::new(),
         sorting_method: SortingMethod::SL
     };
     lock.max_total_size = lock.memory_size/10;
 
     // Consume lock config
-    let mut files: Vec<DirEntry> = Vec::new();
     if let Some(lock_config) = config["lock"].as_table() {
-        lock.max_file_size = size_to_bytes(lock_config["max_file_size"].as_str().unwrap_or("0"), &lock).unwrap_or(lock.max_file_size);
-        lock.max_total_size = size_to_bytes(lock_config["max_total_size"].as_str().unwrap_or("0"), &lock).unwrap_or(lock.max_total_size);
+        let max_file_size = lock_config["max_file_size"].as_str().unwrap_or("0");
+        let max_total_size = lock_config["max_total_size"].as_str().unwrap_or("0");
+        // More than all of memory is most likely a typo
+        for (key, size) in [("max_file_size", max_file_size), ("max_total_size", max_total_size)] {
+            static PERCENT_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"(\d+)%").unwrap());
+            if PERCENT_RE.captures_iter(size).any(|v| v[1].parse::<usize>().is_ok_and(|v| v > 100)) {
+                return Err(format!("{} {} in {} is more than 100% of memory!", key, size, config_file))
+            }
+        }
+        match size_to_bytes(max_file_size, lock.memory_size) {
+            Some(v) => lock.max_file_size = v,
+            None => return Err(format!("max_file_size {} in {} is invalid!", max_file_size, config_file))
+        }
+        match size_to_bytes(max_total_size, lock.memory_size) {
+            Some(v) => lock.max_total_size = v,
+            None => return Err(format!("max_total_size {} in {} is invalid!", max_total_size, config_file))
+        }
         if lock.max_total_size == 0 {
             return Err("Max total size is zero!".to_string())
         }
+        // Set by the administrator outside of the config, nothing in the config can go above it
+        if let Ok(ceiling) = std::env::var("PRELOCKD_MAX_LOCK") {
+            match size_to_bytes(&ceiling, lock.memory_size) {
+                Some(ceiling) if ceiling > 0 => lock.max_lock_ceiling = Some(ceiling),
+                _ => return Err(format!("PRELOCKD_MAX_LOCK {} is invalid!", ceiling))
+            }
+        }
+        lock.max_total_size = clamp_budget(lock.max_total_size, &lock);
+        lock.base_max_total_size = lock.max_total_size;
+        // A fraction is of max_total_size, unlike a percentage in sizes which is of memory
+        if let Some(warn_threshold) = lock_config.get("warn_threshold") {
+            let fraction = match warn_threshold {
+                toml::Value::String(v) if !v.trim().ends_with('%') => None,
+                v => fraction_from_value(v)
+            };
+            lock.warn_threshold = match (fraction, warn_threshold.as_str()) {
+                (Some(v), _) if v > 0.0 && v <= 1.0 => Some(WarnThreshold::Fraction(v)),
+                (None, Some(size)) => size_to_bytes(size, lock.memory_size).filter(|v| *v > 0).map(WarnThreshold::Size),
+                _ => None
+            };
+            if lock.warn_threshold.is_none() {
+                return Err(format!("warn_threshold in {} has to be a fraction of max_total_size or a size!", config_file))
+            }
+        }
+        if let Some(budget_policy) = lock_config.get("budget_policy").and_then(|v| v.as_str()) {
+            lock.budget_policy = match budget_policy.to_lowercase().as_str() {
+                "skip" => BudgetPolicy::Skip,
+                "stop" => BudgetPolicy::Stop,
+                _ => return Err(format!("budget_policy in {} has to be skip or stop!", config_file))
+            };
+        }
+        // Set when building or by the administrator, files outside these roots are never locked whatever the config says
+        let allowed_roots = match (ALLOWED_ROOTS, std::env::var("PRELOCKD_ALLOWED_ROOTS")) {
+            (Some(roots), Ok(_)) => {
+                println!("Ignoring PRELOCKD_ALLOWED_ROOTS, allowed roots were set when building");
+                Some(String::from(roots))
+            }
+            (Some(roots), Err(_)) => Some(String::from(roots)),
+            (None, Ok(roots)) => Some(roots),
+            (None, Err(_)) => None
+        };
+        if let Some(allowed_roots) = allowed_roots {
+            let mut roots = Vec::new();
+            for root in allowed_roots.split(':').filter(|root| !root.is_empty()) {
+                match fs::canonicalize(root) {
+                    Ok(v) => roots.push(v),
+                    Err(err) => println!("Warning: allowed root {} is skipped: {}", root, err)
+                }
+            }
+            lock.allowed_roots = Some(roots);
+        }
+        lock.strict = lock_config.get("strict").and_then(|v| v.as_bool()).unwrap_or(false);
+        if lock.max_file_size > lock.max_total_size {
+            let message = format!("Max file size {} is larger than max total size {}, a single file can take up the whole budget",
+                bytes_to_size(lock.max_file_size), bytes_to_size(lock.max_total_size));
+            if lock.strict {
+                return Err(message)
+            }
+            println!("Warning: {}", message);
+        }
         if lock.max_file_size == 0 {
             return Err("Max file size is zero!".to_string())
         }
+        if let Some(lock_fraction) = lock_config.get("lock_fraction_per_file") {
+            match fraction_from_value(lock_fraction) {
+                Some(v) if v > 0.0 && v <= 1.0 => lock.lock_fraction = v,
+                _ => return Err(format!("lock_fraction_per_file in {} has to be between 0 and 1!", config_file))
+            }
+        }
+        lock.drop_unlocked_remainder = lock_config.get("drop_unlocked_remainder").and_then(|v| v.as_bool()).unwrap_or(false);
+        lock.count_whole_pages = lock_config.get("count_whole_pages").and_then(|v| v.as_bool()).unwrap_or(false);
+        lock.count_overhead = lock_config.get("count_overhead").and_then(|v| v.as_bool()).unwrap_or(false);
+        lock.dedup_hardlinks = lock_config.get("dedup_hardlinks").and_then(|v| v.as_bool()).unwrap_or(false);
+        lock.upgrade_locks = lock_config.get("upgrade_locks").and_then(|v| v.as_bool()).unwrap_or(false);
+        match lock_config.get("load_strategy").and_then(|v| v.as_str()).map(|v| v.to_lowercase()).as_deref() {
+            Some("two_phase") => lock.two_phase = true,
+            Some("interleaved") | None => {}
+            _ => return Err(format!("load_strategy in {} has to be interleaved or two_phase!", config_file))
+        }
+        if let Some(prefetch_concurrency) = lock_config.get("prefetch_concurrency").and_then(|v| v.as_integer()) {
+            if prefetch_concurrency < 0 {
+                return Err(format!("prefetch_concurrency in {} can't be negative!", config_file))
+            }
+            lock.prefetch_concurrency = prefetch_concurrency as usize;
+            if !lock.two_phase && prefetch_concurrency > 0 {
+                let message = format!("prefetch_concurrency in {} only applies to load_strategy two_phase", config_file);
+                if lock.strict {
+                    return Err(message)
+                }
+                println!("Warning: {}", message);
+            }
+        }
+        if let Some(readahead_bytes) = lock_config.get("readahead_bytes").and_then(|v| v.as_str()) {
+            match size_to_bytes(readahead_bytes, lock.memory_size) {
+                // madvise takes page aligned ranges
+                Some(v) => lock.readahead_bytes = if v == 0 { 0 } else { (v/page_size()).max(1)*page_size() },
+                None => return Err(format!("readahead_bytes in {} is invalid!", config_file))
+            }
+        }
+        if let Some(max_mapping_size) = lock_config.get("max_mapping_size").and_then(|v| v.as_str()) {
+            match size_to_bytes(max_mapping_size, lock.memory_size) {
+                // Segments after the first one have to start on a page boundary
+                Some(v) => lock.max_mapping_size = if v == 0 { 0 } else { (v/page_size()).max(1)*page_size() },
+                None => return Err(format!("max_mapping_size in {} is invalid!", config_file))
+            }
+        }
+        if let Some(max_map_fraction) = lock_config.get("max_map_fraction") {
+            match fraction_from_value(max_map_fraction) {
+                Some(v) if v > 0.0 && v <= 1.0 => lock.max_map_fraction = v,
+                _ => return Err(format!("max_map_fraction in {} has to be between 0 and 1!", config_file))
+            }
+        }
+        lock.raise_max_map_count = lock_config.get("raise_max_map_count").and_then(|v| v.as_bool()).unwrap_or(false);
+        if let Some(memlock_limit_policy) = lock_config.get("memlock_limit_policy").and_then(|v| v.as_str()) {
+            lock.memlock_limit_policy = match memlock_limit_policy.to_lowercase().as_str() {
+                "warn" => MemlockLimitPolicy::Warn,
+                "truncate" => MemlockLimitPolicy::Truncate,
+                "error" => MemlockLimitPolicy::Error,
+                _ => return Err(format!("memlock_limit_policy in {} has to be warn, truncate or error!", config_file))
+            };
+        }
+        if let Some(mempolicy) = lock_config.get("mempolicy").and_then(|v| v.as_str()) {
+            lock.mempolicy = match mempolicy.to_lowercase().as_str() {
+                "interleave" => Some(MemPolicy::Interleave),
+                "local" => Some(MemPolicy::Local),
+                policy => match policy.strip_prefix("preferred:").and_then(|v| v.parse::<u32>().ok()) {
+                    Some(node) => Some(MemPolicy::Preferred(node)),
+                    None => return Err(format!("mempolicy in {} has to be interleave, local or preferred:<node>!", config_file))
+                }
+            };
+        }
+        match lock_config.get("mlock_fallback").and_then(|v| v.as_str()).map(|v| v.to_lowercase()).as_deref() {
+            Some("prewarm") => lock.prewarm_fallback = true,
+            Some("fail") | None => {}
+            _ => return Err(format!("mlock_fallback in {} has to be prewarm or fail!", config_file))
+        }
+        lock.refuse_world_writable = lock_config.get("refuse_world_writable").and_then(|v| v.as_bool()).unwrap_or(false);
+        lock.executables_only = lock_config.get("executables_only").and_then(|v| v.as_bool()).unwrap_or(false);
+        for (key, owner) in [("owner_uid", &mut lock.owner_uid), ("owner_gid", &mut lock.owner_gid)] {
+            if let Some(id) = lock_config.get(key) {
+                match id.as_integer().and_then(|v| u32::try_from(v).ok()) {
+                    Some(v) => *owner = Some(v),
+                    None => return Err(format!("{} in {} has to be a positive number!", key, config_file))
+                }
+            }
+        }
+        if let Some(min_success_fraction) = lock_config.get("min_success_fraction") {
+            match fraction_from_value(min_success_fraction) {
+                Some(v) if (0.0..=1.0).contains(&v) => lock.min_success_fraction = v,
+                _ => return Err(format!("min_success_fraction in {} has to be between 0 and 1!", config_file))
+            }
+        }
+        if let Some(min_stable_age) = lock_config.get("min_stable_age").and_then(|v| v.as_str()) {
+            match time_to_duration(min_stable_age) {
+                Some(v) => lock.min_stable_age = Some(v),
+                None => return Err(format!("min_stable_age in {} is invalid!", config_file))
+            }
+        }
         
-        let files = Mutex::new(&mut files);
-        let locations = lock_config["locations"].as_array().expect("locations was not an array!");
-        locations.par_iter().for_each(|location| {
-            let location = location.as_str().expect("locations have to be strings!");
-            if let Ok(location_data) = fs::metadata(location) {
-                if location_data.is_dir() {
-                    if let Ok(location) = fs::read_dir(location) {
-                        for file in location {
-                            if let Ok(file) = file {
-                                if let Ok(file_data) = file.metadata() {
-                                    if file_data.is_file() && file_data.len() as usize <= lock.max_file_size {
-                                        files.lock().unwrap().push(file);
-                                    }
-                                }
-                            }
-                        }
-                    } else {
-                        println!("Couldn't read {}", location);
-                    }
+        if let Some(wait_for_mounts) = lock_config.get("wait_for_mounts").and_then(|v| v.as_array()) {
+            for path in wait_for_mounts {
+                match path.as_str() {
+                    Some(path) if path.len() > 1 => lock.wait_for_mounts.push(String::from(path.trim_end_matches('/'))),
+                    Some(path) => lock.wait_for_mounts.push(String::from(path)),
+                    None => return Err(format!("wait_for_mounts in {} has to be a list of paths!", config_file))
+                }
+            }
+        }
+        if let Some(mount_timeout) = lock_config.get("mount_timeout").and_then(|v| v.as_str()) {
+            match time_to_duration(mount_timeout) {
+                Some(v) => lock.mount_timeout = v,
+                None => return Err(format!("mount_timeout in {} is invalid!", config_file))
+            }
+        }
+        match lock_config.get("mount_timeout_action").and_then(|v| v.as_str()).map(|v| v.to_lowercase()).as_deref() {
+            Some("fail") => lock.mount_timeout_fail = true,
+            Some("proceed") | None => {}
+            _ => return Err(format!("mount_timeout_action in {} has to be proceed or fail!", config_file))
+        }
+
+        let scan_threads = lock_config.get("scan_threads").and_then(|v| v.as_integer()).unwrap_or(0);
+        if scan_threads < 0 {
+            return Err(format!("scan_threads in {} can't be negative!", config_file))
+        }
+        lock.scan_threads = scan_threads as usize;
+        match lock_config.get("scan_nice").map(|v| v.as_integer()) {
+            Some(Some(v)) if (-20..=19).contains(&v) => lock.scan_nice = Some(v as i32),
+            Some(_) => return Err(format!("scan_nice in {} has to be between -20 and 19!", config_file)),
+            None => {}
+        }
+        // Defaults for every location, a location table can set its own
+        let recursive = lock_config.get("recursive").and_then(|v| v.as_bool()).unwrap_or(false);
+        let max_depth = match lock_config.get("max_depth").map(|v| v.as_integer()) {
+            Some(Some(v)) if v >= 0 => Some(v as usize),
+            Some(_) => return Err(format!("max_depth in {} has to be a positive number!", config_file)),
+            None => None
+        };
+        for location in lock_config["locations"].as_array().expect("locations was not an array!") {
+            // A location is either a path or a table with its path in path
+            match location {
+                toml::Value::String(path) => lock.locations.push(Location { path: path.clone(), recursive, max_depth, ..Default::default() }),
+                toml::Value::Table(location) => {
+                    let Some(path) = location.get("path").and_then(|v| v.as_str()) else {
+                        return Err(format!("locations in {} need a path!", config_file))
+                    };
+                    let min_files = location.get("min_files").and_then(|v| v.as_integer()).unwrap_or(0).max(0) as usize;
+                    let max_total_size = match location.get("max_total_size").and_then(|v| v.as_str()) {
+                        Some(size) => match size_to_bytes(size, lock.memory_size) {
+                            Some(v) if v > 0 => Some(v),
+                            _ => return Err(format!("max_total_size of location {} in {} is invalid!", path, config_file))
+                        },
+                        None => None
+                    };
+                    let strings = |key: &str| match location.get(key) {
+                        Some(values) => values.as_array().and_then(|v| v.iter().map(|v| v.as_str().map(String::from)).collect::<Option<Vec<String>>>())
+                            .ok_or(format!("{} of location {} in {} has to be a list of strings!", key, path, config_file)),
+                        None => Ok(Vec::new())
+                    };
+                    let files = strings("files")?;
+                    let lists = strings("lists")?;
+                    let max_depth = match location.get("max_depth").map(|v| v.as_integer()) {
+                        Some(Some(v)) if v >= 0 => Some(v as usize),
+                        Some(_) => return Err(format!("max_depth of location {} in {} has to be a positive number!", path, config_file)),
+                        None => max_depth
+                    };
+                    let recursive = location.get("recursive").and_then(|v| v.as_bool()).unwrap_or(recursive);
+                    let lock_all = location.get("lock_all").and_then(|v| v.as_bool()).unwrap_or(false);
+                    let exclude = exclude_patterns(&strings("exclude")?).map_err(|err| format!("exclude of location {} in {} is invalid: {}", path, config_file, err))?;
+                    let coherent = location.get("coherent").and_then(|v| v.as_bool()).unwrap_or(false);
+                    lock.locations.push(Location { path: String::from(path), min_files, max_total_size, files, lists, recursive, max_depth, lock_all, exclude, coherent, ..Default::default() });
                 }
+                _ => return Err(format!("locations in {} have to be strings or tables!", config_file))
             }
+        }
+        // A location that doesn't exist may just not be mounted yet, one pointing nowhere is likely a mistake
+        for location in lock.locations.iter() {
+            if fs::metadata(&location.path).is_err() && fs::symlink_metadata(&location.path).is_ok_and(|v| v.is_symlink()) {
+                let target = fs::read_link(&location.path).map(|v| v.display().to_string()).unwrap_or_default();
+                let message = format!("Location {} is a symlink to {}, which doesn't exist", location.path, target);
+                if lock.strict {
+                    return Err(message)
+                }
+                println!("Warning: {}", message);
+            }
+        }
+        // The same directory reached through a symlink would be scanned twice
+        let mut canonical_locations: HashMap<PathBuf, String> = HashMap::new();
+        lock.locations.retain(|location| {
+            let Ok(canonical) = fs::canonicalize(&location.path) else {
+                return true
+            };
+            if let Some(first) = canonical_locations.get(&canonical) {
+                println!("Skipping location {}, it is the same as {}", location.path, first);
+                return false
+            }
+            canonical_locations.insert(canonical, location.path.clone());
+            true
         });
 
         if let Some(sorting_method) = lock_config["sorting_method"].as_str() {
@@ -154,6 +1135,14 @@ fn daemon_setup(config_file: &str) -> Result<(), String> {
                     println!("Locking in order of largest to smallest");
                     lock.sorting_method = SortingMethod::LS;
                 }
+                "shallow_first" => {
+                    println!("Locking in order of shallowest to deepest");
+                    lock.sorting_method = SortingMethod::ShallowFirst;
+                }
+                "trace" => {
+                    println!("Locking in order of the access trace");
+                    lock.sorting_method = SortingMethod::Trace;
+                }
                 _ => {
                     println!("Locking in order of smallest to largest");
                     lock.sorting_method = SortingMethod::SL;
@@ -163,102 +1152,3245 @@ fn daemon_setup(config_file: &str) -> Result<(), String> {
     } else {
         return Err(format!("lock table in {} is invalid!", config_file))
     }
-    
-    let to_load: RwLock<Vec<(String, FileInfo)>> = RwLock::new(Vec::new());
 
-    // Find specified files
-    if let Some(load) = config["load"].as_table() {
-        let mut patterns = Vec::new();
-        if let Some(files) = load["files"].as_array() {
-            for pattern in files {
-                patterns.push(pattern.as_str().expect("patterns need to be strings!"));
+    let mut timezone = None;
+    if let Some(daemon_config) = config.get("daemon").and_then(|v| v.as_table()) {
+        lock.state_file = daemon_config.get("state_file").and_then(|v| v.as_str()).map(String::from);
+        if let Some(peer_state_files) = daemon_config.get("peer_state_files") {
+            match peer_state_files.as_array().and_then(|v| v.iter().map(|v| v.as_str().map(String::from)).collect::<Option<Vec<String>>>()) {
+                Some(v) => lock.peer_state_files = v,
+                None => return Err(format!("peer_state_files in {} has to be a list of paths!", config_file))
             }
         }
-
-        if let Some(lists) = load["lists"].as_array() {
-            for list in lists {
-                let list_id = list.as_str().expect("list needs to be a string!");
-                if let Some(list) = load[list_id].as_array() {
-                    for pattern in list {
-                        patterns.push(pattern.as_str().expect(format!("patterns in {} need to be strings!", list_id).as_str()));
-                    }
-                }
+        match daemon_config.get("peer_overlap").and_then(|v| v.as_str()).map(|v| v.to_lowercase()).as_deref() {
+            Some("skip") => lock.skip_peer_files = true,
+            Some("lock") | None => {}
+            _ => return Err(format!("peer_overlap in {} has to be lock or skip!", config_file))
+        }
+        lock.checkpoint_file = daemon_config.get("checkpoint_file").and_then(|v| v.as_str()).map(String::from);
+        lock.status_socket = daemon_config.get("status_socket").and_then(|v| v.as_str()).map(String::from);
+        // Octal either as 0o660 or as the string "660"
+        if let Some(mode) = daemon_config.get("status_socket_mode") {
+            match mode.as_integer().map(u32::try_from).and_then(Result::ok).or_else(|| mode.as_str().and_then(|v| u32::from_str_radix(v, 8).ok())) {
+                Some(v) if v <= 0o777 => lock.status_socket_mode = Some(v),
+                _ => return Err(format!("status_socket_mode in {} has to be octal permission bits like \"660\"!", config_file))
             }
-        } else {
-            return Err(format!("load table in {} is invalid!", config_file))
         }
-            
-        patterns.par_iter().for_each(|pattern| {
-            let re = RegexBuilder::new(format!(r"/{}\z",pattern).as_str()).size_limit(u16::MAX as usize).build().expect("Unable to build regex pattern");
-            for file in files.iter() {
-                if let Some(path) = file.path().to_str() {
-                    if re.is_match(path) {
-                        match file.metadata() {
-                            Ok(file_data) => to_load.write().unwrap().push((String::from(path), FileInfo { size: file_data.len() })),
-                            Err(err) => println!("Unable to get metadata for {}: {}", path, err)
-                        }
-                    }
-                }
+        if let Some(group) = daemon_config.get("status_socket_group") {
+            match group.as_integer().map(u32::try_from).and_then(Result::ok).or_else(|| group.as_str().and_then(group_id)) {
+                Some(v) => lock.status_socket_group = Some(v),
+                None => return Err(format!("status_socket_group in {} has to be a gid or an existing group!", config_file))
             }
-        });
-        files.clear();
+        }
+        if let Some(max_config_age) = daemon_config.get("max_config_age").and_then(|v| v.as_str()) {
+            let Some(max_age) = time_to_duration(max_config_age) else {
+                return Err(format!("max_config_age in {} is invalid!", config_file))
+            };
+            let age = match fs::metadata(config_file).and_then(|v| v.modified()) {
+                Ok(modified) => modified.elapsed().unwrap_or_default(),
+                Err(err) => return Err(format!("Failed to get the modification time of {}: {}", config_file, err))
+            };
+            if age > max_age {
+                return Err(format!("{} was last modified {}s ago, longer than max_config_age {}", config_file, age.as_secs(), max_config_age))
+            }
+        }
+        if let Some(startup_delay) = daemon_config.get("startup_delay").and_then(|v| v.as_str()) {
+            match time_to_duration(startup_delay) {
+                Some(v) => lock.startup_delay = Some(v),
+                None => return Err(format!("startup_delay in {} is invalid!", config_file))
+            }
+        }
+        if let Some(ready_after_fraction) = daemon_config.get("ready_after_fraction") {
+            match fraction_from_value(ready_after_fraction) {
+                Some(v) if (0.0..=1.0).contains(&v) => lock.ready_after_fraction = Some(v),
+                _ => return Err(format!("ready_after_fraction in {} has to be between 0 and 1!", config_file))
+            }
+        }
+        lock.alert_webhook = daemon_config.get("alert_webhook").and_then(|v| v.as_str()).map(String::from);
+        if lock.alert_webhook.is_some() && cfg!(not(feature = "webhook")) {
+            let message = format!("alert_webhook in {} needs prelockd-rs built with the webhook feature", config_file);
+            if lock.strict {
+                return Err(message)
+            }
+            println!("Warning: {}", message);
+        }
+        if let Some(rescan_interval) = daemon_config.get("rescan_interval").and_then(|v| v.as_str()) {
+            match time_to_duration(rescan_interval) {
+                Some(v) if !v.is_zero() => lock.rescan_interval = v,
+                _ => return Err(format!("rescan_interval in {} is invalid!", config_file))
+            }
+        }
+        lock.rescan_jitter = lock.rescan_interval/10;
+        if let Some(rescan_jitter) = daemon_config.get("rescan_jitter") {
+            // Either a fraction of rescan_interval or a duration
+            let jitter = match rescan_jitter.as_str().and_then(time_to_duration) {
+                Some(v) => Some(v),
+                None => fraction_from_value(rescan_jitter).filter(|v| (0.0..=1.0).contains(v)).map(|v| lock.rescan_interval.mul_f64(v))
+            };
+            match jitter {
+                Some(v) if v <= lock.rescan_interval => lock.rescan_jitter = v,
+                _ => return Err(format!("rescan_jitter in {} is invalid!", config_file))
+            }
+        }
+        if let Some(residency_check_interval) = daemon_config.get("residency_check_interval").and_then(|v| v.as_str()) {
+            match time_to_duration(residency_check_interval) {
+                Some(v) if !v.is_zero() => lock.residency_check_interval = Some(v),
+                _ => return Err(format!("residency_check_interval in {} is invalid!", config_file))
+            }
+        }
+        if let Some(residency_retries) = daemon_config.get("residency_retries").and_then(|v| v.as_integer()) {
+            if residency_retries < 1 {
+                return Err(format!("residency_retries in {} has to be at least 1!", config_file))
+            }
+            lock.residency_retries = residency_retries as u32;
+        }
+        if let Some(min_available) = daemon_config.get("min_available").and_then(|v| v.as_str()) {
+            let Some(min_available) = size_to_bytes(min_available, lock.memory_size).filter(|v| *v > 0) else {
+                return Err(format!("min_available in {} is invalid!", config_file))
+            };
+            lock.min_available = Some(min_available);
+            lock.resume_available = match daemon_config.get("resume_available").and_then(|v| v.as_str()) {
+                Some(resume_available) => match size_to_bytes(resume_available, lock.memory_size) {
+                    Some(v) if v >= min_available => v,
+                    _ => return Err(format!("resume_available in {} has to be at least min_available!", config_file))
+                },
+                None => min_available*2
+            };
+        }
+        timezone = daemon_config.get("timezone").and_then(|v| v.as_str()).map(String::from);
     }
 
-    match lock.sorting_method {
-        SortingMethod::SL => to_load.write().unwrap().sort_by(|file_a, file_b| file_a.1.size.cmp(&file_b.1.size)),
-        SortingMethod::LS => to_load.write().unwrap().sort_by(|file_a, file_b| file_b.1.size.cmp(&file_a.1.size)),
-        _ => {}
+    if let Some(schedule) = config.get("schedule").and_then(|v| v.as_array()) {
+        for window in schedule {
+            let Some(window) = window.as_table() else {
+                return Err(format!("schedule in {} has to be a list of tables!", config_file))
+            };
+            let start = window.get("start").and_then(|v| v.as_str()).and_then(time_of_day);
+            let end = window.get("end").and_then(|v| v.as_str()).and_then(time_of_day);
+            let (Some(start), Some(end)) = (start, end) else {
+                return Err(format!("schedule windows in {} need a start and end like \"08:00\"!", config_file))
+            };
+            let lists = match window.get("lists").and_then(|v| v.as_array()) {
+                Some(lists) => lists.iter().filter_map(|v| v.as_str()).map(String::from).collect(),
+                None => return Err(format!("schedule windows in {} need lists!", config_file))
+            };
+            let max_total_size = match window.get("max_total_size").and_then(|v| v.as_str()) {
+                Some(size) => match size_to_bytes(size, lock.memory_size) {
+                    Some(v) if v > 0 => Some(clamp_budget(v, &lock)),
+                    _ => return Err(format!("max_total_size of a schedule window in {} is invalid!", config_file))
+                },
+                None => None
+            };
+            lock.schedule.push(Window { start, end, lists, max_total_size });
+        }
     }
-    let current_size: Mutex<usize> = Mutex::new(0);
-    to_load.read().unwrap().par_iter().for_each(|to_load| {
-        if *current_size.lock().unwrap() + to_load.1.size as usize > lock.max_total_size {
-            return;
+    if let Some(load) = config["load"].as_table() {
+        lock.score_command = load.get("score_command").and_then(|v| v.as_str()).map(String::from);
+        lock.access_trace = load.get("access_trace").and_then(|v| v.as_str()).map(String::from);
+        if lock.sorting_method == SortingMethod::Trace && lock.access_trace.is_none() {
+            return Err(format!("sorting_method trace needs access_trace in the load table of {}!", config_file))
         }
-        let path = to_load.0.clone();
-        if let Ok(file) = fs::File::open(&path) {
-            unsafe {
-                // TODO: Stop relying on the default behavior of Mmap::map
-                if let Ok(mmap) = Mmap::map(&file) {
-                    mmap.lock().expect("Failed to lcoked memory");
-                    *current_size.lock().unwrap() += mmap.len();
-                    LOADED.write().unwrap().push((path, mmap));
-                } else {
-                    println!("Failed to map {} to memory", path);
+        lock.lock_all = load.get("lock_all").and_then(|v| v.as_bool()).unwrap_or(false);
+        lock.match_canonical = load.get("match_canonical").and_then(|v| v.as_bool()).unwrap_or(false);
+        // Each layer is scanned like a location, so files in them are matched and budgeted as usual
+        if let Some(image) = load.get("image").and_then(|v| v.as_str()) {
+            let image_root = load.get("image_root").and_then(|v| v.as_str()).unwrap_or("/var/lib/docker");
+            match image_layers(image_root, image) {
+                Ok(layers) => {
+                    println!("Scanning {} layers of image {}", layers.len(), image);
+                    lock.locations.extend(layers.into_iter().map(|path| Location { path, recursive: true, ..Default::default() }));
+                }
+                Err(err) => {
+                    let message = format!("Not locking image {} from {}, {}", image, config_file, err);
+                    if lock.strict {
+                        return Err(message)
+                    }
+                    println!("Warning: {}", message);
                 }
             }
         }
-    });
-    lock.current_size = *current_size.lock().unwrap();
-
-    unsafe {
-        println!("{} of memory, {} files locked", bytes_to_size(lock.current_size), LOADED.read().unwrap().len());
+        if let Some(exclude) = load.get("exclude") {
+            let exclude = exclude.as_array().and_then(|v| v.iter().map(|v| v.as_str().map(String::from)).collect::<Option<Vec<String>>>())
+                .ok_or(format!("exclude in {} has to be a list of strings!", config_file))?;
+            lock.exclude = exclude_patterns(&exclude).map_err(|err| format!("exclude in {} is invalid: {}", config_file, err))?;
+        }
+        match load.get("list_order").and_then(|v| v.as_str()).map(|v| v.to_lowercase()).as_deref() {
+            Some("round_robin") => lock.round_robin = true,
+            Some("sequential") | None => {}
+            _ => return Err(format!("list_order in {} has to be sequential or round_robin!", config_file))
+        }
+        if let Some(score_timeout) = load.get("score_timeout").and_then(|v| v.as_str()) {
+            match time_to_duration(score_timeout) {
+                Some(v) => lock.score_timeout = v,
+                None => return Err(format!("score_timeout in {} is invalid!", config_file))
+            }
+        }
+        // A list named twice would have its patterns matched twice, a list named like a setting isn't a list
+        const RESERVED: [&str; 13] = ["files", "lists", "magic", "from_pid_fds", "score_command", "score_timeout", "access_trace", "list_order", "lock_all", "match_canonical", "exclude", "image", "image_root"];
+        let mut references: Vec<(String, Vec<&str>)> = vec![(String::from("lists in [load]"),
+            load.get("lists").and_then(|v| v.as_array()).map(|v| v.iter().filter_map(|v| v.as_str()).collect()).unwrap_or_default())];
+        for window in lock.schedule.iter() {
+            references.push((format!("lists of schedule window {:02}:{:02}", window.start/60, window.start%60), window.lists.iter().map(|v| v.as_str()).collect()));
+        }
+        for location in lock.locations.iter() {
+            references.push((format!("lists of location {}", location.path), location.lists.iter().map(|v| v.as_str()).collect()));
+        }
+        for (source, lists) in references {
+            for (index, list) in lists.iter().enumerate() {
+                if RESERVED.contains(list) {
+                    return Err(format!("{} in {} uses the reserved name {}, rename the list!", source, config_file, list))
+                }
+                if lists[..index].contains(list) {
+                    return Err(format!("{} in {} names list {} more than once!", source, config_file, list))
+                }
+            }
+        }
+    }
+    // Appended to files in load, so they use the defaults and win over lists matching the same file
+    if let Ok(json) = std::env::var("PRELOCKD_LOAD_JSON") {
+        let patterns = parse_json_strings(&json).map_err(|err| format!("PRELOCKD_LOAD_JSON is invalid: {}", err))?;
+        for pattern in patterns.iter() {
+            if let Err(err) = Regex::new(&format!(r"/{}\z", pattern)) {
+                return Err(format!("Pattern {} in PRELOCKD_LOAD_JSON is invalid: {}", pattern, err))
+            }
+        }
+        let files = config.get_mut("load").and_then(|v| v.as_table_mut())
+            .and_then(|load| load.entry("files").or_insert(toml::Value::Array(Vec::new())).as_array_mut());
+        let Some(files) = files else {
+            return Err(format!("PRELOCKD_LOAD_JSON needs files in {} to be a list!", config_file))
+        };
+        println!("Adding {} patterns from PRELOCKD_LOAD_JSON", patterns.len());
+        files.extend(patterns.into_iter().map(toml::Value::String));
+    }
+    lock.config = config;
+    if let Err(message) = apply_timezone(timezone, config_file) {
+        if lock.strict {
+            return Err(message)
+        }
+        println!("Warning: {}", message);
+    }
+    lock.active_window = current_window(&lock);
+    if let Some(window) = lock.active_window {
+        lock.max_total_size = lock.schedule[window].max_total_size.unwrap_or(lock.base_max_total_size);
+    }
+    println!("Max file size {} ({} bytes), max total size {} ({} bytes)",
+        bytes_to_size(lock.max_file_size), lock.max_file_size, bytes_to_size(lock.max_total_size), lock.max_total_size);
+    for location in lock.locations.iter() {
+        if let Some(size) = location.max_total_size {
+            println!("Max total size of {} {} ({} bytes)", location.path, bytes_to_size(size), size);
+        }
+    }
+    Ok(lock)
+}
+
+fn daemon_setup<F: Fn(LockEvent) + Sync>(config_file: &str, yaml: bool, resume: bool, warm_from: Option<&str>, startup_delay: Option<Duration>, on_lock: F) -> Result<Lock, String> {
+    let mut lock = read_config(config_file, yaml)?;
+    if let Some(startup_delay) = startup_delay.or(lock.startup_delay).filter(|v| !v.is_zero()) {
+        println!("Waiting {}s before locking", startup_delay.as_secs());
+        let started = Instant::now();
+        while started.elapsed() < startup_delay && !SHUTDOWN.load(Ordering::SeqCst) {
+            std::thread::sleep(startup_delay.saturating_sub(started.elapsed()).min(Duration::from_millis(100)));
+        }
+        // Nothing is locked yet, daemon_run sees SHUTDOWN and returns right away
+        if SHUTDOWN.load(Ordering::SeqCst) {
+            println!("Shutting down before locking");
+            return Ok(lock)
+        }
+    }
+    wait_for_mounts(&lock)?;
+    let resumed = match (&lock.checkpoint_file, resume) {
+        (Some(checkpoint_file), true) => read_checkpoint(checkpoint_file, &lock).unwrap_or_else(|| {
+            println!("No checkpoint in {}, starting from scratch", checkpoint_file);
+            Vec::new()
+        }),
+        (None, true) => return Err(format!("--resume needs checkpoint_file in {}!", config_file)),
+        _ => Vec::new()
+    };
+    // Rewritten from scratch, resumed files get recorded again as they are locked
+    let checkpoint: Mutex<Option<fs::File>> = Mutex::new(match &lock.checkpoint_file {
+        Some(checkpoint_file) => match fs::File::create(checkpoint_file) {
+            Ok(v) => Some(v),
+            Err(err) => return Err(format!("Failed creating {}: {}", checkpoint_file, err))
+        },
+        None => None
+    });
+    // Until the selection is known this goes by max_total_size, resumed and warmed files are locked before that
+    let started = Instant::now();
+    let locked = AtomicUsize::new(0);
+    let ready_at = AtomicUsize::new(lock.ready_after_fraction.map(|v| (lock.max_total_size as f64*v) as usize).unwrap_or(usize::MAX));
+    let ready = AtomicBool::new(false);
+    let (threshold, max_total_size) = (warn_threshold(&lock), lock.max_total_size);
+    let signal_ready = |locked: usize| {
+        if !ready.swap(true, Ordering::SeqCst) {
+            sd_notify("READY=1");
+            println!("Ready after {:.1}s with {} locked, locking the rest", started.elapsed().as_secs_f64(), bytes_to_size(locked));
+        }
+    };
+    let on_lock = |event: LockEvent| {
+        if event.locked {
+            if let Some(checkpoint) = checkpoint.lock().unwrap().as_mut() {
+                let location = event.location.map(|v| v.to_string()).unwrap_or(String::from("-"));
+                let _ = writeln!(checkpoint, "{} {} {}", event.size, location, event.path);
+            }
+            let total = locked.fetch_add(event.size, Ordering::SeqCst) + event.size;
+            if total >= ready_at.load(Ordering::SeqCst) {
+                signal_ready(total);
+            }
+            // Only ever goes up during the pass, update_sizes catches it going back below
+            if let Some(threshold) = threshold.filter(|threshold| total >= *threshold) {
+                check_warn_threshold(total, threshold, max_total_size);
+            }
+        }
+        on_lock(event);
+    };
+
+    let mut intended_size = 0;
+    if !resumed.is_empty() {
+        println!("Resuming with {} files from the checkpoint", resumed.len());
+        intended_size += lock_files_interleaved(&resumed, &lock, on_lock).1;
+    }
+    // What was in the page cache before the last shutdown goes ahead of the usual order
+    if let Some(snapshot_file) = warm_from {
+        match read_checkpoint(snapshot_file, &lock) {
+            // Checked and sized again by read_checkpoint, the snapshot may predate a config or file change
+            Some(warm) => {
+                let warm: Vec<(String, FileInfo)> = warm.into_iter().filter(|file| !resumed.iter().any(|resumed| resumed.0 == file.0)).collect();
+                println!("Warming {} files from the residency snapshot", warm.len());
+                intended_size += lock_files_interleaved(&warm, &lock, on_lock).1;
+            }
+            None => println!("No residency snapshot in {}, locking as usual", snapshot_file)
+        }
+    }
+    let mut to_load = find_files(&lock, None)?;
+    record_stamps(&to_load, &mut lock);
+    // Resumed and warmed files that didn't fit are left in, so they count as dropped
+    let loaded: HashSet<String> = LOADED.read().unwrap().iter().map(|file| file.path.clone()).collect();
+    to_load.retain(|file| !loaded.contains(&file.0));
+    check_commit(to_load.iter().map(|file| file.1.size as usize).sum::<usize>().min(lock.max_total_size.saturating_sub(intended_size)));
+    // Worked out the way locking goes through the budget, to tell whether it is what limits the selection
+    let (dropped, planned) = {
+        let mut budget = Budget::from_loaded(&LOADED.read().unwrap(), &lock);
+        (to_load.len() - budget.select(&to_load, &lock).len(), budget.total)
+    };
+    if let Some(ready_after_fraction) = lock.ready_after_fraction {
+        ready_at.store((planned as f64*ready_after_fraction) as usize, Ordering::SeqCst);
+        if locked.load(Ordering::SeqCst) >= ready_at.load(Ordering::SeqCst) {
+            signal_ready(locked.load(Ordering::SeqCst));
+        }
+    }
+    let selected = to_load.len();
+    fit_memlock_limit(&mut to_load, &lock)?;
+    let memlock_cut = selected - to_load.len();
+    fit_map_count(&mut to_load, &lock);
+    let map_count_cut = selected - memlock_cut - to_load.len();
+    intended_size += lock_files_with(&to_load, &lock, on_lock).1;
+    update_sizes(&mut lock);
+    if let Some(checkpoint_file) = &lock.checkpoint_file {
+        let _ = fs::remove_file(checkpoint_file);
+    }
+    let permission_denied = report_permission_denied();
+    let failed = FAILURES.lock().unwrap().len();
+    send_alerts(&lock);
+    lock.skipped = vec![("budget", dropped), ("memlock_limit", memlock_cut), ("max_map_count", map_count_cut), ("permission_denied", permission_denied), ("failed", failed)];
+    lock.budget_limited = dropped > 0;
+    print_summary(&lock);
+    let used = bytes_to_size(Budget::from_loaded(&LOADED.read().unwrap(), &lock).total);
+    if dropped == 0 {
+        println!("Budget {}, used {} (budget not limiting)", bytes_to_size(lock.max_total_size), used);
+    } else {
+        println!("Budget {}, used {} (budget fully used, {} files didn't fit)", bytes_to_size(lock.max_total_size), used, dropped);
+    }
+    if lock.count_overhead {
+        println!("{} of that is prelockd's own memory", bytes_to_size(read_overhead().unwrap_or(0)));
+    }
+    lock.lock_latency = latency_percentiles(std::mem::take(&mut LOCK_LATENCIES.lock().unwrap()));
+    if let Some(latency) = &lock.lock_latency {
+        println!("mlock took {:.2?} p50, {:.2?} p95, {:.2?} p99, {:.2?} max over {} files", latency.p50, latency.p95, latency.p99, latency.max, latency.count);
+    }
+    if let (Some(vmas), Some(max_map_count)) = (count_vmas(), max_map_count()) {
+        if vmas*10 >= max_map_count*9 {
+            println!("Warning: {} of vm.max_map_count {} mappings are in use", vmas, max_map_count);
+        }
+    }
+    if intended_size > 0 && !SHUTDOWN.load(Ordering::SeqCst) && (lock.current_size as f64) < intended_size as f64*lock.min_success_fraction {
+        return Err(format!("Only {} of {} was locked, below min_success_fraction", bytes_to_size(lock.current_size), bytes_to_size(intended_size)))
+    }
+    if ready.swap(true, Ordering::SeqCst) {
+        println!("Initial pass took {:.1}s", started.elapsed().as_secs_f64());
+    } else {
+        sd_notify("READY=1");
+    }
+    Ok(lock)
+}
+
+/// Sends state to systemd when it started the daemon with a notify socket, otherwise does nothing
+#[cfg(target_os = "linux")]
+fn sd_notify(state: &str) {
+    use std::os::{linux::net::SocketAddrExt, unix::net::{SocketAddr, UnixDatagram}};
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return
+    };
+    let result = UnixDatagram::unbound().and_then(|socket| match path.as_encoded_bytes().strip_prefix(b"@") {
+        // Abstract socket
+        Some(name) => socket.send_to_addr(state.as_bytes(), &SocketAddr::from_abstract_name(name)?),
+        None => socket.send_to(state.as_bytes(), &path)
+    });
+    if let Err(err) = result {
+        println!("Failed to notify systemd through {}: {}", path.to_string_lossy(), err);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn sd_notify(_: &str) {}
+
+/// Layer directories of a local Docker image in the overlay2 store, lowest layer first.
+/// The image config lists its layers by diff id, layerdb has a directory per layer on top of its parent
+/// with the diff id in diff and the overlay2 directory in cache-id.
+#[cfg(feature = "oci")]
+fn image_layers(image_root: &str, image: &str) -> Result<Vec<String>, String> {
+    let store = Path::new(image_root).join("image/overlay2");
+    // Untagged references are to latest, like docker run
+    let name = image.rsplit('/').next().unwrap_or(image);
+    let reference = if name.contains(':') || name.contains('@') { image.to_string() } else { format!("{}:latest", image) };
+    let repositories = fs::read_to_string(store.join("repositories.json")).map_err(|err| format!("failed reading repositories.json: {}", err))?;
+    // Every tag appears once, as a key mapped to the image id
+    let key = json_string(&reference);
+    let id = repositories.find(&key).and_then(|start| {
+        let mut chars = repositories[start+key.len()..].trim_start().strip_prefix(':')?.trim_start().chars().peekable();
+        parse_json_string(&mut chars).ok()
+    }).ok_or(format!("it isn't in {}", store.join("repositories.json").display()))?;
+    let config_file = store.join("imagedb/content/sha256").join(id.trim_start_matches("sha256:"));
+    let config = fs::read_to_string(&config_file).map_err(|err| format!("failed reading {}: {}", config_file.display(), err))?;
+    let diff_ids = config.find("\"diff_ids\"").and_then(|start| {
+        let array = &config[start..];
+        let array = &array[array.find('[')?..=array.find(']')?];
+        parse_json_strings(array).ok()
+    }).ok_or(format!("{} has no diff_ids", config_file.display()))?;
+    let layerdb = store.join("layerdb/sha256");
+    let read = |dir: &Path, file: &str| fs::read_to_string(dir.join(file)).ok().map(|v| v.trim().to_string());
+    let layers: Vec<_> = fs::read_dir(&layerdb).map_err(|err| format!("failed reading {}: {}", layerdb.display(), err))?
+        .flatten().map(|entry| {
+            let dir = entry.path();
+            (format!("sha256:{}", entry.file_name().to_string_lossy()), read(&dir, "diff"), read(&dir, "parent"), read(&dir, "cache-id"))
+        }).collect();
+    let mut parent: Option<String> = None;
+    let mut paths = Vec::with_capacity(diff_ids.len());
+    for diff_id in diff_ids {
+        // The same layer under another parent is a different chain
+        let Some((chain, _, _, cache_id)) = layers.iter().find(|layer| layer.1.as_ref() == Some(&diff_id) && layer.2 == parent) else {
+            return Err(format!("layer {} is missing from {}", diff_id, layerdb.display()))
+        };
+        let Some(cache_id) = cache_id else {
+            return Err(format!("layer {} has no cache-id", diff_id))
+        };
+        paths.push(Path::new(image_root).join("overlay2").join(cache_id).join("diff").to_string_lossy().into_owned());
+        parent = Some(chain.clone());
+    }
+    Ok(paths)
+}
+
+#[cfg(not(feature = "oci"))]
+fn image_layers(_: &str, _: &str) -> Result<Vec<String>, String> {
+    Err(String::from("image needs prelockd-rs built with the oci feature"))
+}
+
+struct Mount {
+    device: u64,
+    mount_point: String,
+    fs_type: String,
+    source: String
+}
+
+/// Mounts from /proc/self/mountinfo, fields have spaces and such escaped in octal
+fn mounts() -> Vec<Mount> {
+    let Ok(mountinfo) = fs::read_to_string("/proc/self/mountinfo") else {
+        return Vec::new()
+    };
+    let unescape = |field: &str| {
+        let mut unescaped = String::with_capacity(field.len());
+        let mut chars = field.chars();
+        while let Some(c) = chars.next() {
+            if c == '\\' {
+                let code: String = chars.by_ref().take(3).collect();
+                match u8::from_str_radix(&code, 8) {
+                    Ok(v) => unescaped.push(v as char),
+                    Err(_) => {
+                        unescaped.push(c);
+                        unescaped.push_str(&code);
+                    }
+                }
+            } else {
+                unescaped.push(c);
+            }
+        }
+        unescaped
+    };
+    mountinfo.lines().filter_map(|line| {
+        let fields: Vec<&str> = line.split(' ').collect();
+        let (major, minor) = fields.get(2)?.split_once(':')?;
+        // Optional fields end with a lone -, then come the fs type and source
+        let separator = fields.iter().position(|field| *field == "-")?;
+        Some(Mount {
+            device: libc::makedev(major.parse().ok()?, minor.parse().ok()?),
+            mount_point: unescape(fields.get(4)?),
+            fs_type: unescape(fields.get(separator+1)?),
+            source: unescape(fields.get(separator+2)?)
+        })
+    }).collect()
+}
+
+fn mount_points() -> HashSet<String> {
+    mounts().into_iter().map(|mount| mount.mount_point).collect()
+}
+
+/// The mount a path is on, the deepest mount point above it on the same device as the path
+fn mount_of<'a>(path: &str, mounts: &'a [Mount]) -> Option<&'a Mount> {
+    let device = fs::metadata(path).ok().map(|v| v.dev());
+    let above: Vec<&Mount> = mounts.iter().filter(|mount| Path::new(path).starts_with(&mount.mount_point)).collect();
+    // Some filesystems like btrfs give files a device that isn't in mountinfo
+    let on_device: Vec<&Mount> = above.iter().copied().filter(|mount| Some(mount.device) == device).collect();
+    let candidates = if on_device.is_empty() { above } else { on_device };
+    // Later mounts over the same point hide the earlier ones
+    candidates.into_iter().rev().max_by_key(|mount| mount.mount_point.len())
+}
+
+/// Blocks until every path in wait_for_mounts is a mount point or mount_timeout passes
+fn wait_for_mounts(lock: &Lock) -> Result<(), String> {
+    if lock.wait_for_mounts.is_empty() {
+        return Ok(())
+    }
+    let started = Instant::now();
+    let mut waiting = false;
+    loop {
+        let mount_points = mount_points();
+        let missing: Vec<&str> = lock.wait_for_mounts.iter().filter(|path| {
+            // Symlinked paths show up resolved in mountinfo
+            let canonical = fs::canonicalize(path).ok().and_then(|v| v.to_str().map(String::from));
+            !mount_points.contains(path.as_str()) && !canonical.is_some_and(|v| mount_points.contains(&v))
+        }).map(|path| path.as_str()).collect();
+        if missing.is_empty() {
+            if waiting {
+                println!("Mounts are ready after {}s", started.elapsed().as_secs());
+            }
+            return Ok(())
+        }
+        if started.elapsed() >= lock.mount_timeout {
+            let message = format!("{} not mounted after {}s", missing.join(", "), lock.mount_timeout.as_secs());
+            if lock.mount_timeout_fail {
+                return Err(message)
+            }
+            println!("Warning: {}, scanning anyway", message);
+            return Ok(())
+        }
+        if !waiting {
+            println!("Waiting for {} to be mounted", missing.join(", "));
+            waiting = true;
+        }
+        std::thread::sleep(Duration::from_secs(1));
+    }
+}
+
+/// Files recorded by an interrupted start as "<size> <location> <path>" lines, checked against the
+/// filters of the scan again, files that are gone, shrank or no longer pass are left to be found again
+fn read_checkpoint(checkpoint_file: &str, lock: &Lock) -> Option<Vec<(String, FileInfo)>> {
+    let checkpoint = fs::read_to_string(checkpoint_file).ok()?;
+    let mut files = Vec::new();
+    for line in checkpoint.lines() {
+        let mut fields = line.splitn(3, ' ');
+        let (Some(size), Some(location), Some(path)) = (fields.next(), fields.next(), fields.next()) else {
+            continue
+        };
+        let Ok(size) = size.parse::<u64>() else {
+            continue
+        };
+        let Ok(file_data) = fs::metadata(path) else {
+            continue
+        };
+        let location = location.parse::<usize>().ok().filter(|v| *v < lock.locations.len());
+        if !is_candidate(Path::new(path), &file_data, lock) || is_excluded(path, lock, location) {
+            continue;
+        }
+        if lock.allowed_roots.is_some() && !fs::canonicalize(path).is_ok_and(|path| is_allowed(&path, lock)) {
+            continue;
+        }
+        // Sized from the file as it is now, not as it was recorded
+        let mut info = FileInfo::new(&file_data, lock);
+        if info.size < size {
+            continue;
+        }
+        info.location = location;
+        files.push((String::from(path), info));
+    }
+    Some(files)
+}
+
+/// Writes the candidates and loaded files that are in the page cache, most resident first,
+/// in the format of checkpoint_file so --warm-from-residency can read it back
+fn save_residency(snapshot_file: &str, lock: &Lock) -> Result<(), String> {
+    let loaded = LOADED.read().unwrap();
+    let mut files: Vec<(f64, usize, Option<usize>, String)> = loaded.iter().map(|file| {
+        let pages = file.mmap.page_aligned_len(page_size())/page_size();
+        (resident_pages(&file.mmap) as f64/pages.max(1) as f64, file.mmap.len(), file.location, file.path.clone())
+    }).collect();
+    let loaded_paths: HashSet<&str> = loaded.iter().map(|file| file.path.as_str()).collect();
+    // Candidates that aren't loaded are mapped just long enough to ask mincore
+    for (path, info) in find_files(lock, None)?.into_iter().filter(|file| !loaded_paths.contains(file.0.as_str())) {
+        let size = info.size as usize;
+        let Ok(file) = fs::File::open(&path) else {
+            continue
+        };
+        let Ok(mmap) = (unsafe { MmapOptions::new().len(size).map(&file) }) else {
+            continue
+        };
+        let mapping = Mapping { segments: vec![mmap] };
+        let resident = resident_pages(&mapping);
+        if resident > 0 {
+            files.push((resident as f64/size.div_ceil(page_size()) as f64, size, info.location, path));
+        }
+    }
+    files.sort_by(|a, b| b.0.total_cmp(&a.0));
+    let snapshot: String = files.iter().map(|(_, size, location, path)| {
+        format!("{} {} {}\n", size, location.map(|v| v.to_string()).unwrap_or(String::from("-")), path)
+    }).collect();
+    fs::write(snapshot_file, snapshot).map_err(|err| format!("Failed writing {}: {}", snapshot_file, err))?;
+    println!("Saved {} resident files to {}", files.len(), snapshot_file);
+    Ok(())
+}
+
+/// YAML is read into the same table as TOML, so both go through the same settings
+#[cfg(feature = "yaml")]
+fn parse_yaml(config_data: &str) -> Result<Table, String> {
+    serde_yaml::from_str::<Table>(config_data).map_err(|err| err.to_string())
+}
+
+#[cfg(not(feature = "yaml"))]
+fn parse_yaml(_: &str) -> Result<Table, String> {
+    Err("YAML configs need prelockd-rs built with the yaml feature".to_string())
+}
+
+/// Whether the config is YAML, from --config-format or else the extension
+fn config_is_yaml(config_file: &str, config_format: Option<&str>) -> Result<bool, String> {
+    match config_format.map(|v| v.to_lowercase()).as_deref() {
+        Some("yaml") | Some("yml") => Ok(true),
+        Some("toml") => Ok(false),
+        Some(format) => Err(format!("Unknown config format {}, use toml or yaml", format)),
+        None => Ok(config_file.ends_with(".yaml") || config_file.ends_with(".yml"))
+    }
+}
+
+/// PRELOCKD_MAX_LOCK caps every budget
+fn clamp_budget(size: usize, lock: &Lock) -> usize {
+    match lock.max_lock_ceiling {
+        Some(ceiling) if size > ceiling => {
+            println!("Max total size {} is above PRELOCKD_MAX_LOCK, clamping to {}", bytes_to_size(size), bytes_to_size(ceiling));
+            ceiling
+        }
+        _ => size
+    }
+}
+
+/// "HH:MM" as minutes since midnight
+fn time_of_day(time: &str) -> Option<u32> {
+    let (hours, minutes) = time.trim().split_once(':')?;
+    let hours = hours.parse::<u32>().ok()?;
+    let minutes = minutes.parse::<u32>().ok()?;
+    if hours > 23 || minutes > 59 {
+        return None
+    }
+    Some(hours*60 + minutes)
+}
+
+/// Sets TZ for localtime_r the first time a config is read, which is before any thread is started.
+/// Changing the environment later could race with other threads reading it, so reloads only get told it didn't change.
+fn apply_timezone(timezone: Option<String>, config_file: &str) -> Result<(), String> {
+    static APPLIED: std::sync::OnceLock<Option<String>> = std::sync::OnceLock::new();
+    let applied = APPLIED.get_or_init(|| {
+        if let Some(timezone) = &timezone {
+            // zoneinfo takes care of DST
+            std::env::set_var("TZ", timezone);
+            extern "C" {
+                fn tzset();
+            }
+            unsafe { tzset() };
+        }
+        timezone.clone()
+    });
+    if *applied != timezone {
+        return Err(format!("timezone in {} changed to {}, that only takes effect on restart, still using {}", config_file,
+            timezone.as_deref().unwrap_or("the system timezone"), applied.as_deref().unwrap_or("the system timezone")))
+    }
+    Ok(())
+}
+
+fn local_minutes() -> u32 {
+    unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        libc::localtime_r(&now, &mut tm);
+        (tm.tm_hour*60 + tm.tm_min) as u32
+    }
+}
+
+/// The first schedule window the local time is in, windows ending before they start go past midnight
+fn current_window(lock: &Lock) -> Option<usize> {
+    if lock.schedule.is_empty() {
+        return None
+    }
+    let now = local_minutes();
+    lock.schedule.iter().position(|window| {
+        if window.start <= window.end {
+            now >= window.start && now < window.end
+        } else {
+            now >= window.start || now < window.end
+        }
+    })
+}
+
+fn update_sizes(lock: &mut Lock) {
+    let loaded = LOADED.read().unwrap();
+    lock.current_size = loaded.iter().filter(|file| file.locked).map(|file| file.mmap.len()).sum();
+    lock.prewarm_size = loaded.iter().filter(|file| !file.locked).map(|file| file.mmap.len()).sum();
+    drop(loaded);
+    if let Some(threshold) = warn_threshold(lock) {
+        check_warn_threshold(lock.current_size, threshold, lock.max_total_size);
+    }
+}
+
+/// warn_threshold in bytes, for the budget of the moment
+fn warn_threshold(lock: &Lock) -> Option<usize> {
+    match lock.warn_threshold? {
+        WarnThreshold::Fraction(v) => Some((lock.max_total_size as f64*v) as usize),
+        WarnThreshold::Size(v) => Some(v)
+    }
+}
+
+/// Warns the first time locked reaches warn_threshold, and again only after it went back below
+fn check_warn_threshold(locked: usize, threshold: usize, max_total_size: usize) {
+    static WARNED: AtomicBool = AtomicBool::new(false);
+    if locked < threshold {
+        WARNED.store(false, Ordering::SeqCst);
+    } else if !WARNED.swap(true, Ordering::SeqCst) {
+        println!("Warning: {} locked reached warn_threshold {} of max_total_size {}", bytes_to_size(locked), bytes_to_size(threshold), bytes_to_size(max_total_size));
+    }
+}
+
+/// Brings the loaded files in line with to_load and the budget.
+/// Files that stay selected keep their mapping, so they are never unlocked in between.
+/// Returns whether any file was locked or unlocked.
+fn sync_loaded(to_load: &[(String, FileInfo)], lock: &mut Lock) -> bool {
+    // The new selection decides what is locked, files evicted before it don't come back on their own
+    lock.evicted.clear();
+    let mut loaded = LOADED.write().unwrap();
+    let loaded_sizes: HashMap<String, usize> = loaded.iter().map(|file| (file.path.clone(), file.mmap.len())).collect();
+    // Files that stay loaded take up what their mapping has, not what the file has grown or shrunk to
+    let sized: Vec<(String, FileInfo)> = to_load.iter().map(|file| {
+        let mut file = file.clone();
+        if let Some(size) = loaded_sizes.get(&file.0) {
+            file.1.size = *size as u64;
+        }
+        file
+    }).collect();
+    let mut keep = HashMap::new();
+    let mut added = Vec::new();
+    for file in Budget::new(lock).select(&sized, lock) {
+        if loaded_sizes.contains_key(&file.0) {
+            keep.insert(file.0.as_str(), &file.1);
+        } else {
+            added.push(file.clone());
+        }
+    }
+    let before = loaded.len();
+    // Dropping a mapping unlocks it
+    loaded.retain(|file| keep.contains_key(file.path.as_str()));
+    let removed = before - loaded.len();
+    // Settings can change on reload while the mapping stays as it is
+    for file in loaded.iter_mut() {
+        let info = keep[file.path.as_str()];
+        file.aliases = info.aliases.clone();
+        file.track_growth = info.track_growth;
+        file.location = info.location;
+    }
+    drop(loaded);
+    fit_memlock_limit_running(&mut added, lock);
+    lock_files_with(&added, lock, |_| {});
+    update_sizes(lock);
+    record_stamps(to_load, lock);
+    report_permission_denied();
+    if removed == 0 && added.is_empty() {
+        return false
+    }
+    println!("Unlocked {} files, locked {} files, {} of memory locked", removed, added.len(), bytes_to_size(lock.current_size));
+    true
+}
+
+/// What is held after locking, at startup and after a reload
+fn print_summary(lock: &Lock) {
+    let loaded = LOADED.read().unwrap();
+    println!("{} of memory, {} files locked", bytes_to_size(lock.current_size), loaded.iter().filter(|file| file.locked).count());
+    if lock.prewarm_size > 0 {
+        let prewarmed = loaded.iter().filter(|file| !file.locked).count();
+        if lock.upgrade_locks {
+            println!("{} of memory, {} files prewarmed until they can be locked", bytes_to_size(lock.prewarm_size), prewarmed);
+        } else {
+            println!("{} of memory, {} files prewarmed but not locked", bytes_to_size(lock.prewarm_size), prewarmed);
+        }
+    }
+    // The kernel pins a page once however many instances lock it, so this is memory counted twice
+    if !lock.peer_state_files.is_empty() {
+        let peer_inodes = peer_inodes(lock);
+        let shared: Vec<usize> = loaded.iter().filter(|file| fs::metadata(&file.path).is_ok_and(|v| peer_inodes.contains(&(v.dev(), v.ino())))).map(|file| file.mmap.len()).collect();
+        if !shared.is_empty() {
+            println!("{} of memory, {} files also locked by other instances, it only takes up memory once", bytes_to_size(shared.iter().sum()), shared.len());
+        }
+    }
+    drop(loaded);
+    print_location_stats(lock);
+}
+
+fn report_permission_denied() -> usize {
+    let denied = PERMISSION_DENIED.swap(0, Ordering::Relaxed);
+    if denied > 0 {
+        println!("Warning: {} files couldn't be opened, permission denied", denied);
+    }
+    denied
+}
+
+/// Switches to the lists and budget of a schedule window, or back to the config outside of them
+fn apply_window(lock: &mut Lock, window: Option<usize>) -> bool {
+    match window {
+        Some(index) => {
+            let window = &lock.schedule[index];
+            println!("Entering schedule window {:02}:{:02}-{:02}:{:02}", window.start/60, window.start%60, window.end/60, window.end%60);
+            lock.max_total_size = window.max_total_size.unwrap_or(lock.base_max_total_size);
+        }
+        None => {
+            println!("Leaving schedule windows");
+            lock.max_total_size = lock.base_max_total_size;
+        }
+    }
+    lock.active_window = window;
+    match find_files(lock, None) {
+        Ok(to_load) => {
+            sync_loaded(&to_load, lock);
+            print_summary(lock);
+            true
+        }
+        Err(err) => {
+            println!("Failed to find files: {}", err);
+            false
+        }
+    }
+}
+
+/// Scans the locations and matches the files against the load table, in the order they should be locked
+/// When candidates is given every match is also recorded there as (pattern or source, path), before anything is filtered out
+fn find_files(lock: &Lock, candidates: Option<&Mutex<Vec<(String, String)>>>) -> Result<Vec<(String, FileInfo)>, String> {
+    let config = &lock.config;
+    let config_file = &lock.config_file;
+    // Only the scan threads are lowered, they go away with the pool so nothing needs restoring
+    let scan_nice = lock.scan_nice;
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(lock.scan_threads).start_handler(move |_| {
+        if let Some(nice) = scan_nice {
+            set_thread_priority(nice);
+        }
+    });
+    let pool = match pool.build() {
+        Ok(v) => v,
+        Err(err) => return Err(format!("Failed to create scan threads: {}", err))
+    };
+    // Results are collected in the order of locations, so fl stays stable no matter which thread finishes first
+    let scanned = pool.install(|| lock.locations.par_iter().map(|location| {
+        scan_location(location, lock)
+    }).collect::<Vec<Vec<DirEntry>>>());
+    for (location, found) in lock.locations.iter().zip(scanned.iter()) {
+        location.scanned.store(found.len(), Ordering::Relaxed);
+    }
+    // Each file with the path patterns and exclude are matched against
+    let files: Vec<(DirEntry, String, usize)> = scanned.into_iter().enumerate().flat_map(|(index, found)| {
+        found.into_iter().map(move |file| (file, index))
+    }).filter_map(|(file, index)| {
+        let path = if lock.match_canonical { fs::canonicalize(file.path()).ok()? } else { file.path() };
+        let path = path.to_str()?.to_string();
+        (!is_excluded(&path, lock, Some(index))).then_some((file, path, index))
+    }).collect();
+    
+    let to_load: RwLock<Vec<(String, FileInfo)>> = RwLock::new(Vec::new());
+
+    // Find specified files
+    if let Some(load) = config["load"].as_table() {
+        // Patterns point into list_options, files in load uses the defaults
+        let mut patterns = Vec::new();
+        let mut list_options = vec![ListOptions::default()];
+        let mut list_names = vec!["files"];
+        if let Some(files) = load["files"].as_array() {
+            for pattern in files {
+                patterns.push((pattern.as_str().expect("patterns need to be strings!"), 0, None));
+            }
+        }
+        for (index, location) in lock.locations.iter().enumerate() {
+            if !location.files.is_empty() {
+                list_options.push(ListOptions::default());
+                list_names.push(location.path.as_str());
+                patterns.extend(location.files.iter().map(|pattern| (pattern.as_str(), list_options.len()-1, Some(index))));
+            }
+        }
+
+        let lists: Option<Vec<&str>> = match lock.active_window {
+            Some(window) => Some(lock.schedule[window].lists.iter().map(|v| v.as_str()).collect()),
+            None => load["lists"].as_array().map(|lists| lists.iter().map(|list| list.as_str().expect("list needs to be a string!")).collect())
+        };
+        if let Some(lists) = lists {
+            // Lists of a location only apply to files found there
+            let mut scoped_lists: Vec<(&str, Option<usize>)> = lists.into_iter().map(|list| (list, None)).collect();
+            for (index, location) in lock.locations.iter().enumerate() {
+                scoped_lists.extend(location.lists.iter().map(|list| (list.as_str(), Some(index))));
+            }
+            for (list_id, scope) in scoped_lists {
+                // A list is either an array of patterns or a table with its patterns in files
+                let (list, options) = match load.get(list_id) {
+                    Some(toml::Value::Array(list)) => (list, ListOptions::default()),
+                    Some(toml::Value::Table(list)) => {
+                        let keep_latest = match list.get("keep_latest").and_then(|v| v.as_integer()) {
+                            Some(count) if count > 0 => {
+                                let version = list.get("version_pattern").and_then(|v| v.as_str()).unwrap_or(r"\d+(\.\d+)*");
+                                let version = match Regex::new(version) {
+                                    Ok(v) => v,
+                                    Err(err) => return Err(format!("version_pattern of list {} in {} is invalid: {}", list_id, config_file, err))
+                                };
+                                let by_mtime = match list.get("keep_latest_by").and_then(|v| v.as_str()).map(|v| v.to_lowercase()).as_deref() {
+                                    Some("mtime") => true,
+                                    Some("version") | None => false,
+                                    _ => return Err(format!("keep_latest_by of list {} in {} has to be version or mtime!", list_id, config_file))
+                                };
+                                Some(KeepLatest { count: count as usize, version, by_mtime })
+                            }
+                            Some(_) => return Err(format!("keep_latest of list {} in {} has to be above zero!", list_id, config_file)),
+                            None => None
+                        };
+                        let options = ListOptions {
+                            track_growth: list.get("track_growth").and_then(|v| v.as_bool()).unwrap_or(false),
+                            keep_latest,
+                            all_or_nothing: list.get("all_or_nothing").and_then(|v| v.as_bool()).unwrap_or(false)
+                        };
+                        match list.get("files").and_then(|v| v.as_array()) {
+                            Some(files) => (files, options),
+                            None => return Err(format!("list {} in {} has no files!", list_id, config_file))
+                        }
+                    }
+                    _ => continue
+                };
+                list_options.push(options);
+                list_names.push(list_id);
+                for pattern in list {
+                    patterns.push((pattern.as_str().unwrap_or_else(|| panic!("patterns in {} need to be strings!", list_id)), list_options.len()-1, scope));
+                }
+            }
+        } else {
+            return Err(format!("load table in {} is invalid!", config_file))
+        }
+            
+        let matched: Mutex<Vec<(usize, String, FileInfo)>> = Mutex::new(Vec::new());
+        patterns.par_iter().for_each(|(pattern, list, scope)| {
+            let options = &list_options[*list];
+            let re = RegexBuilder::new(format!(r"/{}\z",pattern).as_str()).size_limit(u16::MAX as usize).build().expect("Unable to build regex pattern");
+            for (file, path, location) in files.iter().filter(|(_, _, location)| scope.is_none_or(|scope| scope == *location)) {
+                if re.is_match(path) {
+                    match file.metadata() {
+                        Ok(file_data) => {
+                            let mut info = FileInfo::new(&file_data, lock);
+                            info.track_growth = options.track_growth;
+                            info.location = Some(*location);
+                            info.all_or_nothing = options.all_or_nothing.then(|| format!("all_or_nothing list {}", list_names[*list]));
+                            info.list = Some(*list);
+                            matched.lock().unwrap().push((*list, path.clone(), info));
+                            if let Some(candidates) = candidates {
+                                candidates.lock().unwrap().push((format!("{}: {}", list_names[*list], pattern), path.clone()));
+                            }
+                        }
+                        Err(err) => println!("Unable to get metadata for {}: {}", path, err)
+                    }
+                }
+            }
+        });
+        let mut matched = matched.into_inner().unwrap();
+        for (list, options) in list_options.iter().enumerate() {
+            if let Some(keep_latest) = &options.keep_latest {
+                keep_latest_versions(&mut matched, list, keep_latest);
+            }
+        }
+        to_load.write().unwrap().extend(matched.into_iter().map(|(_, path, info)| (path, info)));
+
+        if let Some(magic) = load.get("magic").and_then(|v| v.as_array()) {
+            let mut types = Vec::new();
+            for file_type in magic {
+                match file_type.as_str().map(|v| v.to_lowercase()).as_deref() {
+                    Some("elf") => types.push(FileType::Elf),
+                    Some("exec") => types.push(FileType::Exec),
+                    Some("so") => types.push(FileType::So),
+                    _ => return Err(format!("magic in {} only supports elf, exec and so!", config_file))
+                }
+            }
+            let mut to_load = to_load.write().unwrap();
+            let matched: HashSet<String> = to_load.iter().map(|file| file.0.clone()).collect();
+            let found: Vec<(String, FileInfo)> = files.par_iter().filter_map(|(file, path, location)| {
+                if matched.contains(path) {
+                    return None
+                }
+                let file_type = detect_file_type(path)?;
+                if !types.iter().any(|v| *v == FileType::Elf || *v == file_type) {
+                    return None
+                }
+                let file_data = file.metadata().ok()?;
+                let mut info = FileInfo::new(&file_data, lock);
+                info.location = Some(*location);
+                Some((path.clone(), info))
+            }).collect();
+            if let Some(candidates) = candidates {
+                candidates.lock().unwrap().extend(found.iter().map(|file| (String::from("magic"), file.0.clone())));
+            }
+            to_load.extend(found);
+        }
+
+        let lock_all: Vec<&(DirEntry, String, usize)> = files.iter().filter(|(_, _, location)| lock.lock_all || lock.locations[*location].lock_all).collect();
+        if !lock_all.is_empty() {
+            let mut to_load = to_load.write().unwrap();
+            let matched: HashSet<String> = to_load.iter().map(|file| file.0.clone()).collect();
+            let found: Vec<(String, FileInfo)> = lock_all.into_iter().filter_map(|(file, path, location)| {
+                if matched.contains(path) {
+                    return None
+                }
+                let mut info = FileInfo::new(&file.metadata().ok()?, lock);
+                info.location = Some(*location);
+                Some((path.clone(), info))
+            }).collect();
+            if let Some(candidates) = candidates {
+                candidates.lock().unwrap().extend(found.iter().map(|file| (String::from("lock_all"), file.0.clone())));
+            }
+            to_load.extend(found);
+        }
+
+        if let Some(pids) = load.get("from_pid_fds").and_then(|v| v.as_array()) {
+            let mut to_load = to_load.write().unwrap();
+            let mut matched: HashSet<String> = to_load.iter().map(|file| file.0.clone()).collect();
+            for pid in pids {
+                let Some(pid) = pid.as_integer() else {
+                    return Err(format!("from_pid_fds in {} has to be a list of pids!", config_file))
+                };
+                for file in scan_pid_fds(pid, lock).into_iter().filter(|file| !is_excluded(&file.0, lock, None)) {
+                    if let Some(candidates) = candidates {
+                        candidates.lock().unwrap().push((format!("from_pid_fds: {}", pid), file.0.clone()));
+                    }
+                    if matched.insert(file.0.clone()) {
+                        to_load.push(file);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut to_load = to_load.into_inner().unwrap();
+    // Only listing, no need to order them or run score_command
+    if candidates.is_some() {
+        return Ok(to_load)
+    }
+    if lock.allowed_roots.is_some() {
+        to_load.retain(|file| {
+            let allowed = fs::canonicalize(&file.0).is_ok_and(|path| is_allowed(&path, lock));
+            if !allowed {
+                println!("Rejecting {}, it is outside of the allowed roots", file.0);
+            }
+            allowed
+        });
+    }
+    if lock.skip_peer_files {
+        let peer_inodes = peer_inodes(lock);
+        let before = to_load.len();
+        to_load.retain(|file| !peer_inodes.contains(&file.1.inode));
+        if to_load.len() < before {
+            println!("Skipping {} files, other instances already lock them", before - to_load.len());
+        }
+    }
+    // Patterns are matched in parallel and read_dir has no order, by path the same tree always gives the same selection
+    to_load.sort_by(|a, b| a.0.cmp(&b.0).then(a.1.list.cmp(&b.1.list)));
+    // A file matched by several lists takes the settings of the first of them, files before location files before lists in order
+    to_load.dedup_by(|a, b| a.0 == b.0);
+    if lock.dedup_hardlinks {
+        dedup_hardlinks(&mut to_load);
+    }
+    for (index, location) in lock.locations.iter().enumerate() {
+        let matched = to_load.iter().filter(|file| file.1.location == Some(index)).count();
+        location.matched.store(matched, Ordering::Relaxed);
+        // Files that are excluded or match no pattern don't count
+        if matched < location.min_files {
+            let message = format!("Only matched {} files in {}, expected at least {}", matched, location.path, location.min_files);
+            if lock.strict {
+                return Err(message)
+            }
+            println!("Warning: {}", message);
+        }
+    }
+    for file in to_load.iter_mut() {
+        if let Some(location) = file.1.location.map(|v| &lock.locations[v]).filter(|location| location.coherent) {
+            file.1.all_or_nothing = Some(format!("coherent location {}", location.path));
+        }
+    }
+
+    match lock.sorting_method {
+        SortingMethod::SL => to_load.sort_by_key(|file| file.1.size),
+        SortingMethod::LS => to_load.sort_by_key(|file| std::cmp::Reverse(file.1.size)),
+        SortingMethod::ShallowFirst => to_load.sort_by_key(|file| file.0.matches('/').count()),
+        SortingMethod::Trace => {
+            let trace = lock.access_trace.as_deref().map(read_trace).unwrap_or_default();
+            // Files the trace doesn't have go after it, smallest first
+            to_load.sort_by_cached_key(|file| match fs::canonicalize(&file.0).ok().and_then(|path| trace.get(&path)) {
+                Some(position) => (false, *position, 0),
+                None => (true, 0, file.1.size)
+            });
+        }
+        _ => {}
+    }
+    if let Some(command) = &lock.score_command {
+        match score_files(command, &to_load, lock.score_timeout) {
+            // Stable, so the sorting method still decides between equal scores and unscored files
+            Ok(scores) => to_load.sort_by(|file_a, file_b| {
+                let score_a = scores.get(&file_a.0).copied().unwrap_or(f64::NEG_INFINITY);
+                let score_b = scores.get(&file_b.0).copied().unwrap_or(f64::NEG_INFINITY);
+                score_b.total_cmp(&score_a)
+            }),
+            Err(err) => println!("Scoring with {} failed, using the sorting method: {}", command, err)
+        }
+    }
+    if lock.round_robin {
+        to_load = round_robin(to_load);
+    }
+    Ok(to_load)
+}
+
+/// Numbers in a version from most to least significant, "1.2.10" is newer than "1.2.9"
+fn version_key(version: &str) -> Vec<u64> {
+    version.split(|c: char| !c.is_ascii_digit()).filter_map(|v| v.parse::<u64>().ok()).collect()
+}
+
+/// Drops all but the newest keep_latest.count versions of every artifact matched by list,
+/// files without a version in their name are kept
+fn keep_latest_versions(matched: &mut Vec<(usize, String, FileInfo)>, list: usize, keep_latest: &KeepLatest) {
+    let mut groups: HashMap<String, Vec<usize>> = HashMap::new();
+    for (index, file) in matched.iter().enumerate().filter(|(_, file)| file.0 == list) {
+        let (dir, name) = file.1.rsplit_once('/').unwrap_or(("", &file.1));
+        if keep_latest.version.is_match(name) {
+            groups.entry(format!("{}/{}", dir, keep_latest.version.replace(name, ""))).or_default().push(index);
+        }
+    }
+    let mut dropped = HashSet::new();
+    for mut group in groups.into_values() {
+        if keep_latest.by_mtime {
+            group.sort_by_key(|index| std::cmp::Reverse(fs::metadata(&matched[*index].1).and_then(|v| v.modified()).ok()));
+        } else {
+            group.sort_by_key(|index| {
+                let name = matched[*index].1.rsplit('/').next().unwrap_or_default();
+                std::cmp::Reverse(keep_latest.version.find(name).map(|v| version_key(v.as_str())))
+            });
+        }
+        dropped.extend(group.into_iter().skip(keep_latest.count));
+    }
+    let mut index = 0;
+    matched.retain(|_| {
+        index += 1;
+        !dropped.contains(&(index-1))
+    });
+}
+
+/// Canonical paths of the access trace with their position, the first access of a path counts
+fn read_trace(trace_file: &str) -> HashMap<PathBuf, usize> {
+    let trace = match fs::read_to_string(trace_file) {
+        Ok(v) => v,
+        Err(err) => {
+            println!("Failed reading access trace {}, locking by size: {}", trace_file, err);
+            return HashMap::new()
+        }
+    };
+    let mut positions = HashMap::new();
+    for line in trace.lines().map(|line| line.trim()).filter(|line| !line.is_empty()) {
+        if let Ok(path) = fs::canonicalize(line) {
+            let position = positions.len();
+            positions.entry(path).or_insert(position);
+        }
+    }
+    positions
+}
+
+/// Takes the next file of every list in turn, each list keeps its own order.
+/// Files that weren't matched by a list come last.
+fn round_robin(to_load: Vec<(String, FileInfo)>) -> Vec<(String, FileInfo)> {
+    let total = to_load.len();
+    let (to_load, unlisted): (Vec<_>, Vec<_>) = to_load.into_iter().partition(|file| file.1.list.is_some());
+    let mut order = Vec::new();
+    let mut lists: Vec<VecDeque<(String, FileInfo)>> = Vec::new();
+    for file in to_load {
+        match order.iter().position(|list| *list == file.1.list) {
+            Some(position) => lists[position].push_back(file),
+            None => {
+                order.push(file.1.list);
+                lists.push(VecDeque::from([file]));
+            }
+        }
+    }
+    let mut ordered = Vec::with_capacity(total);
+    while lists.iter().any(|list| !list.is_empty()) {
+        for list in lists.iter_mut() {
+            if let Some(file) = list.pop_front() {
+                ordered.push(file);
+            }
+        }
+    }
+    ordered.extend(unlisted);
+    ordered
+}
+
+/// Runs command with "<path> <size>" lines on stdin and reads back "<path> <score>" lines
+fn score_files(command: &str, to_load: &[(String, FileInfo)], timeout: Duration) -> Result<HashMap<String, f64>, String> {
+    let mut child = Command::new("/bin/sh").arg("-c").arg(command)
+        .stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::inherit())
+        .spawn().map_err(|err| err.to_string())?;
+    let input: String = to_load.iter().map(|file| format!("{} {}\n", file.0, file.1.size)).collect();
+    let mut stdin = child.stdin.take().unwrap();
+    // Written from another thread so a command that doesn't read its input can't block us past the timeout
+    let writer = std::thread::spawn(move || {
+        let _ = stdin.write_all(input.as_bytes());
+    });
+    let mut stdout = child.stdout.take().unwrap();
+    let reader = std::thread::spawn(move || {
+        let mut output = String::new();
+        let _ = stdout.read_to_string(&mut output);
+        output
+    });
+    let started = Instant::now();
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) if status.success() => break,
+            Ok(Some(status)) => return Err(format!("exited with {}", status)),
+            Ok(None) if started.elapsed() > timeout => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err("timed out".to_string())
+            }
+            Ok(None) => std::thread::sleep(Duration::from_millis(10)),
+            Err(err) => return Err(err.to_string())
+        }
+    }
+    let _ = writer.join();
+    let output = reader.join().unwrap_or_default();
+    let mut scores = HashMap::new();
+    for line in output.lines() {
+        // Paths can contain spaces, the score is always last
+        if let Some((path, score)) = line.trim_end().rsplit_once(' ') {
+            if let Ok(score) = score.parse::<f64>() {
+                scores.insert(String::from(path), score);
+            }
+        }
+    }
+    Ok(scores)
+}
+
+/// Pages of a mapping currently in the page cache, from mincore
+fn resident_pages(mmap: &Mapping) -> usize {
+    let mut resident = 0;
+    for segment in mmap.segments.iter().filter(|segment| !segment.is_empty()) {
+        let mut pages = vec![0u8; segment.len().div_ceil(page_size())];
+        if unsafe { libc::mincore(segment.as_ptr() as *mut libc::c_void, segment.len(), pages.as_mut_ptr()) } == 0 {
+            resident += pages.iter().filter(|page| *page & 1 == 1).count();
+        }
+    }
+    resident
+}
+
+/// Online NUMA nodes as a bitmask, from /sys/devices/system/node/online ("0-3,5")
+#[cfg(target_os = "linux")]
+fn online_nodes() -> u64 {
+    let Ok(online) = fs::read_to_string("/sys/devices/system/node/online") else {
+        return 1
+    };
+    let mut mask = 0u64;
+    for range in online.trim().split(',') {
+        let (start, end) = range.split_once('-').unwrap_or((range, range));
+        if let (Ok(start), Ok(end)) = (start.parse::<u32>(), end.parse::<u32>()) {
+            for node in start..=end.min(63) {
+                mask |= 1 << node;
+            }
+        }
+    }
+    if mask == 0 { 1 } else { mask }
+}
+
+/// Sets the NUMA policy of the calling thread, None goes back to the default.
+/// Page cache pages are placed by the policy of the thread reading them in, mbind is ignored for shared mappings.
+#[cfg(target_os = "linux")]
+fn set_mempolicy(policy: Option<MemPolicy>) {
+    static WARNED: AtomicBool = AtomicBool::new(false);
+    const MPOL_DEFAULT: i64 = 0;
+    const MPOL_PREFERRED: i64 = 1;
+    const MPOL_INTERLEAVE: i64 = 3;
+    const MPOL_LOCAL: i64 = 4;
+    let (mode, nodemask) = match policy {
+        None => (MPOL_DEFAULT, 0),
+        Some(MemPolicy::Interleave) => (MPOL_INTERLEAVE, online_nodes()),
+        Some(MemPolicy::Local) => (MPOL_LOCAL, 0),
+        Some(MemPolicy::Preferred(node)) => (MPOL_PREFERRED, 1u64 << node.min(63))
+    };
+    // The kernel reads one bit less than maxnode
+    let maxnode: u64 = if nodemask == 0 { 0 } else { 65 };
+    let nodemask_ptr = if nodemask == 0 { std::ptr::null() } else { &nodemask as *const u64 };
+    let result = unsafe { libc::syscall(libc::SYS_set_mempolicy, mode, nodemask_ptr, maxnode) };
+    if result != 0 && !WARNED.swap(true, Ordering::Relaxed) {
+        println!("Failed to set mempolicy: {}", std::io::Error::last_os_error());
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_mempolicy(_: Option<MemPolicy>) {}
+
+/// Applies mempolicy to the calling thread while files are read in and locked, restored when dropped
+/// so rayon threads don't keep it for other work
+struct ThreadMempolicy(bool);
+
+impl ThreadMempolicy {
+    fn new(policy: Option<MemPolicy>) -> ThreadMempolicy {
+        if policy.is_some() {
+            set_mempolicy(policy);
+        }
+        ThreadMempolicy(policy.is_some())
+    }
+}
+
+impl Drop for ThreadMempolicy {
+    fn drop(&mut self) {
+        if self.0 {
+            set_mempolicy(None);
+        }
+    }
+}
+
+/// Maps the first size bytes of a file, split into segments of max_mapping_size when it is set
+fn map_file(path: &str, size: usize, lock: &Lock) -> Option<Mapping> {
+    let file = match fs::File::open(path) {
+        Ok(v) => v,
+        Err(err) => {
+            if err.kind() == std::io::ErrorKind::PermissionDenied {
+                println!("Warning: permission denied opening {} (errno {})", path, err.raw_os_error().unwrap_or(0));
+                PERMISSION_DENIED.fetch_add(1, Ordering::Relaxed);
+            } else {
+                println!("Failed to open {}: {}", path, err);
+            }
+            record_failure(path, format!("open: {}", err));
+            return None
+        }
+    };
+    // Checked on what was actually opened, the path could have been swapped for a symlink since it was selected
+    if lock.allowed_roots.is_some() && !fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd())).is_ok_and(|path| is_allowed(&path, lock)) {
+        println!("Rejecting {}, it is outside of the allowed roots", path);
+        record_failure(path, String::from("outside of the allowed roots"));
+        return None
+    }
+    // A writer can truncate the file after it was scanned, pages past the end would fault when locked
+    if !still_fits(&file, path, size) {
+        return None
+    }
+    let segment_size = if lock.max_mapping_size == 0 { size.max(1) } else { lock.max_mapping_size };
+    let mut segments = Vec::new();
+    let mut offset = 0;
+    loop {
+        let len = segment_size.min(size - offset);
+        match unsafe { MmapOptions::new().offset(offset as u64).len(len).map(&file) } {
+            Ok(mmap) => {
+                debug_assert!(is_read_only(&mmap), "{} was mapped writable", path);
+                segments.push(mmap)
+            }
+            Err(err) => {
+                println!("Failed to map {} to memory: {}", path, err);
+                record_failure(path, format!("mmap: {}", err));
+                return None
+            }
+        }
+        offset += len;
+        if offset >= size {
+            break;
+        }
+    }
+    // Checked again for a truncation between the first check and mapping
+    if !still_fits(&file, path, size) {
+        return None
+    }
+    if lock.drop_unlocked_remainder {
+        drop_remainder(&file, path, size);
+    }
+    Some(Mapping { segments })
+}
+
+/// Advises the kernel to drop the cached pages past size, the remainder isn't mapped so madvise can't reach it.
+/// A page that is only partly past size stays, the kernel only drops whole pages.
+fn drop_remainder(file: &fs::File, path: &str, size: usize) {
+    let Ok(file_data) = file.metadata() else {
+        return
+    };
+    if file_data.len() as usize <= size {
+        return
+    }
+    let len = file_data.len() as usize - size;
+    let result = unsafe { libc::posix_fadvise(file.as_raw_fd(), size as libc::off_t, len as libc::off_t, libc::POSIX_FADV_DONTNEED) };
+    if result != 0 {
+        println!("Failed to drop the unlocked {} of {} from the cache (errno {})", bytes_to_size(len), path, result);
+    }
+}
+
+/// Checks the permissions of the mapping in /proc/self/maps, writing to a read-only mapping faults
+fn is_read_only(mmap: &Mmap) -> bool {
+    if mmap.is_empty() {
+        return true
+    }
+    let start = format!("{:08x}-", mmap.as_ptr() as usize);
+    let Ok(maps) = fs::read_to_string("/proc/self/maps") else {
+        return true
+    };
+    match maps.lines().find(|line| line.starts_with(&start)) {
+        Some(line) => line.split(' ').nth(1).is_some_and(|perms| perms.as_bytes().get(1) == Some(&b'-')),
+        None => true
+    }
+}
+
+/// Whether the open file is still at least size long, logging why it's skipped otherwise
+fn still_fits(file: &fs::File, path: &str, size: usize) -> bool {
+    match file.metadata() {
+        Ok(file_data) if file_data.len() == 0 => {
+            println!("Skipping {}, it is empty", path);
+            false
+        }
+        Ok(file_data) if (file_data.len() as usize) < size => {
+            println!("Skipping {}, it was truncated to {} while loading", path, bytes_to_size(file_data.len() as usize));
+            false
+        }
+        Ok(_) => true,
+        Err(err) => {
+            println!("Skipping {}, unable to get metadata: {}", path, err);
+            false
+        }
+    }
+}
+
+/// Locks a mapping, prewarming it instead when locking fails and the config allows it.
+/// Returns whether the mapping got locked, None when it failed to lock and should be dropped.
+fn lock_mapping(mmap: &Mapping, path: &str, lock: &Lock, prewarm_only: &AtomicBool) -> Option<bool> {
+    if prewarm_only.load(Ordering::Relaxed) {
+        record_failure(path, String::from("mlock: not permitted"));
+        mmap.prewarm(lock.readahead_bytes);
+        return Some(false)
+    }
+    let started = Instant::now();
+    match mmap.lock() {
+        Ok(_) => {
+            LOCK_LATENCIES.lock().unwrap().push(started.elapsed());
+            Some(true)
+        }
+        // EPERM means mlock is denied outright (no CAP_IPC_LOCK with a zero limit, or seccomp)
+        Err(err) if lock.prewarm_fallback && err.raw_os_error() == Some(libc::EPERM) => {
+            if !prewarm_only.swap(true, Ordering::Relaxed) {
+                println!("mlock is not permitted, falling back to prewarming; files are not guaranteed to stay in memory");
+            }
+            record_failure(path, format!("mlock: {}", err));
+            mmap.prewarm(lock.readahead_bytes);
+            Some(false)
+        }
+        Err(err) if lock.upgrade_locks => {
+            // Keep it in the page cache until locking becomes possible
+            record_failure(path, format!("mlock: {}", err));
+            mmap.prewarm(lock.readahead_bytes);
+            Some(false)
+        }
+        // Usually RLIMIT_MEMLOCK, or EPERM under mlock_fallback = "fail", the file is left out and the ones already locked stay locked
+        Err(err) => {
+            println!("Failed to lock {}: {}", path, err);
+            record_failure(path, format!("mlock: {}", err));
+            None
+        }
+    }
+}
+
+/// Maps and locks files in order until max_total_size is reached, calling on_lock for every mapped file.
+/// Returns the amount of memory mapped and the amount that fit in the budget and should have been mapped.
+fn lock_files_with<F: Fn(LockEvent) + Sync>(to_load: &[(String, FileInfo)], lock: &Lock, on_lock: F) -> (usize, usize) {
+    let before = LOADED.read().unwrap().len();
+    let (lists, rest): (Vec<_>, Vec<_>) = to_load.iter().cloned().partition(|file| file.1.all_or_nothing.is_some());
+    let committed = lock_all_or_nothing(&lists, lock, &on_lock);
+    let (mapped, intended) = if lock.two_phase {
+        lock_files_two_phase(&rest, lock, on_lock)
+    } else {
+        lock_files_interleaved(&rest, lock, on_lock)
+    };
+    // Files are locked in parallel and pushed as they finish, in to_load order they are unlocked
+    // from the end in the reverse of the order they were ranked in
+    let position: HashMap<&str, usize> = to_load.iter().enumerate().map(|(index, file)| (file.0.as_str(), index)).collect();
+    LOADED.write().unwrap()[before..].sort_by_key(|file| position.get(file.path.as_str()).copied().unwrap_or(usize::MAX));
+    (mapped + committed, intended + committed)
+}
+
+/// Locks the files of each all_or_nothing list or coherent location into a staging set that is only added to the loaded files
+/// and the budget once all of them are locked. Any failure drops the staged mappings, which unlocks them.
+/// Returns the amount of memory committed.
+fn lock_all_or_nothing<F: Fn(LockEvent)>(to_load: &[(String, FileInfo)], lock: &Lock, on_lock: &F) -> usize {
+    let mut names: Vec<&str> = Vec::new();
+    for file in to_load {
+        if let Some(name) = file.1.all_or_nothing.as_deref() {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    let mut committed = 0;
+    for name in names {
+        let files: Vec<&(String, FileInfo)> = to_load.iter().filter(|file| file.1.all_or_nothing.as_deref() == Some(name)).collect();
+        let mut budget = Budget::from_loaded(&LOADED.read().unwrap(), lock);
+        if !files.iter().all(|file| budget.try_reserve(file.1.location, file.1.size as usize, lock)) {
+            println!("Skipping {}, it doesn't fit the budget", name);
+            continue;
+        }
+        let staged = match stage_files(&files, lock) {
+            Ok(v) => v,
+            Err(reason) => {
+                println!("Rolled back {}, {}", name, reason);
+                continue;
+            }
+        };
+        let mut loaded = LOADED.write().unwrap();
+        for (file, mmap, cached) in staged {
+            let size = mmap.len();
+            committed += size;
+            on_lock(LockEvent { path: &file.0, size, total: committed, locked: true, cached, pages: size.div_ceil(page_size()), location: file.1.location });
+            loaded.push(LoadedFile { path: file.0.clone(), mmap, locked: true, aliases: file.1.aliases.clone(), track_growth: file.1.track_growth, location: file.1.location, all_or_nothing: file.1.all_or_nothing.clone(), relock_attempts: 0 });
+        }
+    }
+    committed
+}
+
+/// A file with its locked mapping and the pages that were cached before
+type Staged<'a> = (&'a (String, FileInfo), Mapping, usize);
+
+/// Maps and locks every file, stopping at the first one that fails, which drops the ones before it
+fn stage_files<'a>(files: &[&'a (String, FileInfo)], lock: &Lock) -> Result<Vec<Staged<'a>>, String> {
+    let mut staged = Vec::with_capacity(files.len());
+    let _mempolicy = ThreadMempolicy::new(lock.mempolicy);
+    for file in files {
+        let Some(mmap) = map_file(&file.0, file.1.size as usize, lock) else {
+            return Err(format!("{} couldn't be mapped", file.0))
+        };
+        let cached = resident_pages(&mmap);
+        let started = Instant::now();
+        if let Err(err) = mmap.lock() {
+            record_failure(&file.0, format!("mlock: {}", err));
+            return Err(format!("{} couldn't be locked: {}", file.0, err))
+        }
+        LOCK_LATENCIES.lock().unwrap().push(started.elapsed());
+        staged.push((*file, mmap, cached));
+    }
+    Ok(staged)
+}
+
+/// Stamps of the files of to_load from location, sorted by path
+fn location_stamps(to_load: &[(String, FileInfo)], location: usize) -> Vec<(String, Stamp)> {
+    let mut stamps: Vec<(String, Stamp)> = to_load.iter().filter(|file| file.1.location == Some(location)).filter_map(|file| {
+        let file_data = fs::metadata(&file.0).ok()?;
+        Some((file.0.clone(), Stamp { inode: (file_data.dev(), file_data.ino()), size: file_data.len(), mtime: (file_data.mtime(), file_data.mtime_nsec()) }))
+    }).collect();
+    stamps.sort_by(|a, b| a.0.cmp(&b.0));
+    stamps
+}
+
+/// Remembers what the coherent locations looked like when to_load was locked
+fn record_stamps(to_load: &[(String, FileInfo)], lock: &mut Lock) {
+    for index in 0..lock.locations.len() {
+        if lock.locations[index].coherent {
+            lock.locations[index].stamps = location_stamps(to_load, index);
+        }
+    }
+}
+
+/// Locks the set of every coherent location with a changed, added or removed file again as a whole.
+/// The old set stays locked until the new one is, then both are swapped under one write lock,
+/// so for a moment both take up memory and the set is never partly old and partly new.
+fn refresh_coherent(lock: &mut Lock) -> bool {
+    if !lock.locations.iter().any(|location| location.coherent) {
+        return false
+    }
+    let to_load = match find_files(lock, None) {
+        Ok(v) => v,
+        Err(err) => {
+            println!("Failed to find files: {}", err);
+            return false
+        }
+    };
+    let mut changed = false;
+    for index in 0..lock.locations.len() {
+        if !lock.locations[index].coherent {
+            continue;
+        }
+        let stamps = location_stamps(&to_load, index);
+        if stamps == lock.locations[index].stamps {
+            continue;
+        }
+        let path = lock.locations[index].path.clone();
+        let files: Vec<&(String, FileInfo)> = to_load.iter().filter(|file| file.1.location == Some(index)).collect();
+        // Counted without the old set, it goes away once the new one is locked
+        let mut budget = Budget::new(lock);
+        for file in LOADED.read().unwrap().iter().filter(|file| file.location != Some(index)) {
+            budget.add(file.location, file.mmap.len());
+        }
+        if !files.iter().all(|file| budget.try_reserve(file.1.location, file.1.size as usize, lock)) {
+            println!("Keeping the old set of coherent location {}, the new one doesn't fit the budget", path);
+            continue;
+        }
+        let staged = match stage_files(&files, lock) {
+            Ok(v) => v,
+            Err(reason) => {
+                println!("Keeping the old set of coherent location {}, {}", path, reason);
+                continue;
+            }
+        };
+        let count = staged.len();
+        let mut loaded = LOADED.write().unwrap();
+        let (old, kept): (Vec<LoadedFile>, Vec<LoadedFile>) = std::mem::take(&mut *loaded).into_iter().partition(|file| file.location == Some(index));
+        *loaded = kept;
+        for (file, mmap, _) in staged {
+            loaded.push(LoadedFile { path: file.0.clone(), mmap, locked: true, aliases: file.1.aliases.clone(), track_growth: file.1.track_growth, location: file.1.location, all_or_nothing: file.1.all_or_nothing.clone(), relock_attempts: 0 });
+        }
+        drop(loaded);
+        // Dropping the old mappings unlocks them
+        drop(old);
+        println!("Coherent location {} changed, locked its {} files again as a set", path, count);
+        lock.locations[index].stamps = stamps;
+        changed = true;
+    }
+    if changed {
+        update_sizes(lock);
+    }
+    changed
+}
+
+/// Maps, reads in and locks each file in turn
+fn lock_files_interleaved<F: Fn(LockEvent) + Sync>(to_load: &[(String, FileInfo)], lock: &Lock, on_lock: F) -> (usize, usize) {
+    let current_size: Mutex<usize> = Mutex::new(0);
+    // Picked in order before any locking, so the same files get the budget however the threads are scheduled
+    let selected = Budget::from_loaded(&LOADED.read().unwrap(), lock).select(to_load, lock);
+    let intended_size: usize = selected.iter().map(|file| file.1.size as usize).sum();
+    let prewarm_only = AtomicBool::new(false);
+    selected.into_par_iter().for_each(|to_load| {
+        // SIGTERM during the pass, the rest isn't worth locking just to be unlocked again
+        if SHUTDOWN.load(Ordering::SeqCst) {
+            return
+        }
+        let path = to_load.0.clone();
+        let _mempolicy = ThreadMempolicy::new(lock.mempolicy);
+        if let Some(mmap) = map_file(&path, to_load.1.size as usize, lock) {
+            let cached = resident_pages(&mmap);
+            // Its share of the budget stays unused, the selection was made up front and nothing is picked in its place
+            let Some(locked) = lock_mapping(&mmap, &path, lock, &prewarm_only) else {
+                return
+            };
+            let size = mmap.len();
+            let total = {
+                let mut current_size = current_size.lock().unwrap();
+                *current_size += size;
+                *current_size
+            };
+            on_lock(LockEvent { path: &path, size, total, locked, cached, pages: size.div_ceil(page_size()), location: to_load.1.location });
+            LOADED.write().unwrap().push(LoadedFile { path, mmap, locked, aliases: to_load.1.aliases.clone(), track_growth: to_load.1.track_growth, location: to_load.1.location, all_or_nothing: to_load.1.all_or_nothing.clone(), relock_attempts: 0 });
+        }
+    });
+    (current_size.into_inner().unwrap(), intended_size)
+}
+
+/// Reads every file that fits the budget into the page cache first and only then locks them,
+/// so locking doesn't wait on I/O one file at a time
+fn lock_files_two_phase<F: Fn(LockEvent) + Sync>(to_load: &[(String, FileInfo)], lock: &Lock, on_lock: F) -> (usize, usize) {
+    // The budget is decided up front, both phases work on the same files
+    let mut budget = Budget::from_loaded(&LOADED.read().unwrap(), lock);
+    let selected = budget.select(to_load, lock);
+    let intended_size: usize = selected.iter().map(|file| file.1.size as usize).sum();
+
+    let prefetch = || selected.par_iter().filter_map(|file| {
+        if SHUTDOWN.load(Ordering::SeqCst) {
+            return None
+        }
+        let _mempolicy = ThreadMempolicy::new(lock.mempolicy);
+        let mmap = map_file(&file.0, file.1.size as usize, lock)?;
+        let cached = resident_pages(&mmap);
+        mmap.prewarm(lock.readahead_bytes);
+        Some((*file, mmap, cached))
+    }).collect();
+    // Readahead gets its own pool so it can run wider than locking
+    let mapped: Vec<(&(String, FileInfo), Mapping, usize)> = if lock.prefetch_concurrency > 0 {
+        match rayon::ThreadPoolBuilder::new().num_threads(lock.prefetch_concurrency).build() {
+            Ok(pool) => pool.install(prefetch),
+            Err(err) => {
+                println!("Failed to create prefetch threads: {}", err);
+                prefetch()
+            }
+        }
+    } else {
+        prefetch()
+    };
+
+    let current_size: Mutex<usize> = Mutex::new(0);
+    let prewarm_only = AtomicBool::new(false);
+    mapped.into_par_iter().for_each(|(file, mmap, cached)| {
+        // Pages the readahead didn't get are read in by mlock
+        let _mempolicy = ThreadMempolicy::new(lock.mempolicy);
+        let Some(locked) = lock_mapping(&mmap, &file.0, lock, &prewarm_only) else {
+            return
+        };
+        let size = mmap.len();
+        let total = {
+            let mut current_size = current_size.lock().unwrap();
+            *current_size += size;
+            *current_size
+        };
+        on_lock(LockEvent { path: &file.0, size, total, locked, cached, pages: size.div_ceil(page_size()), location: file.1.location });
+        LOADED.write().unwrap().push(LoadedFile { path: file.0.clone(), mmap, locked, aliases: file.1.aliases.clone(), track_growth: file.1.track_growth, location: file.1.location, all_or_nothing: file.1.all_or_nothing.clone(), relock_attempts: 0 });
+    });
+    (current_size.into_inner().unwrap(), intended_size)
+}
+
+/// Retries locking prewarmed files, stopping at the first failure since the rest would fail the same way
+fn upgrade_locks(lock: &mut Lock) -> bool {
+    let mut loaded = LOADED.write().unwrap();
+    let mut upgraded = 0;
+    for file in loaded.iter_mut().filter(|file| !file.locked) {
+        if file.mmap.lock().is_err() {
+            break;
+        }
+        file.locked = true;
+        lock.current_size += file.mmap.len();
+        lock.prewarm_size -= file.mmap.len();
+        upgraded += 1;
+    }
+    if upgraded > 0 {
+        println!("Upgraded {} prewarmed files to locked, {} of memory locked", upgraded, bytes_to_size(lock.current_size));
+    }
+    upgraded > 0
+}
+
+fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len()+2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c)
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Reads a JSON array of strings, the only JSON prelockd takes in
+fn parse_json_strings(json: &str) -> Result<Vec<String>, String> {
+    let mut chars = json.trim().chars().peekable();
+    let skip_whitespace = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+    };
+    if chars.next() != Some('[') {
+        return Err(String::from("expected an array"))
+    }
+    let mut strings = Vec::new();
+    skip_whitespace(&mut chars);
+    if chars.next_if_eq(&']').is_some() {
+        return if chars.next().is_none() { Ok(strings) } else { Err(String::from("trailing characters")) }
+    }
+    loop {
+        skip_whitespace(&mut chars);
+        strings.push(parse_json_string(&mut chars)?);
+        skip_whitespace(&mut chars);
+        match chars.next() {
+            Some(',') => continue,
+            Some(']') if chars.next().is_none() => return Ok(strings),
+            _ => return Err(String::from("expected , or ]"))
+        }
+    }
+}
+
+/// Reads one JSON string, starting at its opening quote
+fn parse_json_string(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<String, String> {
+    if chars.next() != Some('"') {
+        return Err(String::from("expected a string"))
+    }
+    let mut value = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(value),
+            Some('\\') => match chars.next() {
+                Some('"') => value.push('"'),
+                Some('\\') => value.push('\\'),
+                Some('/') => value.push('/'),
+                Some('b') => value.push('\u{8}'),
+                Some('f') => value.push('\u{c}'),
+                Some('n') => value.push('\n'),
+                Some('r') => value.push('\r'),
+                Some('t') => value.push('\t'),
+                Some('u') => {
+                    let code = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+                        let hex: String = chars.by_ref().take(4).collect();
+                        u32::from_str_radix(&hex, 16).map_err(|_| format!("invalid escape \\u{}", hex))
+                    };
+                    let mut unit = code(chars)?;
+                    // Characters outside the basic plane come as a surrogate pair
+                    if (0xd800..0xdc00).contains(&unit) && chars.next() == Some('\\') && chars.next() == Some('u') {
+                        unit = 0x10000 + ((unit - 0xd800) << 10) + (code(chars)?.wrapping_sub(0xdc00) & 0x3ff);
+                    }
+                    value.push(char::from_u32(unit).ok_or(format!("invalid character {:x}", unit))?);
+                }
+                _ => return Err(String::from("invalid escape"))
+            },
+            Some(c) => value.push(c),
+            None => return Err(String::from("unterminated string"))
+        }
+    }
+}
+
+/// Paths of the files another instance has locked, with their aliases, from the state file it publishes.
+/// Only takes the layout write_state writes, None for anything else.
+fn read_peer_state(state_file: &str) -> Option<Vec<String>> {
+    let state = fs::read_to_string(state_file).ok()?;
+    let start = state.find("\"files\":[")? + "\"files\":[".len();
+    let mut chars = state[start..].chars().peekable();
+    let expect = |chars: &mut std::iter::Peekable<std::str::Chars>, text: &str| text.chars().all(|c| chars.next() == Some(c));
+    let mut paths = Vec::new();
+    loop {
+        match chars.next()? {
+            ']' => return Some(paths),
+            ',' => continue,
+            '{' => {}
+            _ => return None
+        }
+        if !expect(&mut chars, "\"path\":") {
+            return None
+        }
+        let path = parse_json_string(&mut chars).ok()?;
+        if !expect(&mut chars, ",\"size\":") {
+            return None
+        }
+        while chars.next_if(|c| c.is_ascii_digit()).is_some() {}
+        if !expect(&mut chars, ",\"locked\":") {
+            return None
+        }
+        let locked = chars.peek() == Some(&'t');
+        if !expect(&mut chars, if locked { "true" } else { "false" }) || !expect(&mut chars, ",\"aliases\":[") {
+            return None
+        }
+        let mut aliases = Vec::new();
+        while chars.peek() == Some(&'"') {
+            aliases.push(parse_json_string(&mut chars).ok()?);
+            chars.next_if_eq(&',');
+        }
+        if !expect(&mut chars, "]}") {
+            return None
+        }
+        if locked {
+            paths.push(path);
+            paths.extend(aliases);
+        }
+    }
+}
+
+/// Inodes of the files locked by the instances behind peer_state_files
+fn peer_inodes(lock: &Lock) -> HashSet<(u64, u64)> {
+    let mut inodes = HashSet::new();
+    for state_file in lock.peer_state_files.iter().filter(|state_file| lock.state_file.as_ref() != Some(*state_file)) {
+        let Some(paths) = read_peer_state(state_file) else {
+            // Not running, or not done with its first pass
+            continue
+        };
+        inodes.extend(paths.iter().filter_map(|path| fs::metadata(path).ok()).map(|file_data| (file_data.dev(), file_data.ino())));
+    }
+    inodes
+}
+
+/// Publishes the loaded files for other tools, the file is replaced with a rename so readers never see it half written
+fn write_state(lock: &Lock) {
+    let Some(state_file) = &lock.state_file else {
+        return
+    };
+    let loaded = LOADED.read().unwrap();
+    let files: Vec<String> = loaded.iter().map(|file| {
+        let aliases: Vec<String> = file.aliases.iter().map(|alias| json_string(alias)).collect();
+        format!("{{\"path\":{},\"size\":{},\"locked\":{},\"aliases\":[{}]}}", json_string(&file.path), file.mmap.len(), file.locked, aliases.join(","))
+    }).collect();
+    let locations: Vec<String> = lock.locations.iter().zip(location_usage(&loaded, lock)).map(|(location, (locked, size))| {
+        format!("{{\"path\":{},\"scanned\":{},\"matched\":{},\"locked\":{},\"size\":{}}}", json_string(&location.path),
+            location.scanned.load(Ordering::Relaxed), location.matched.load(Ordering::Relaxed), locked, size)
+    }).collect();
+    let latency = match &lock.lock_latency {
+        Some(latency) => format!(",\"lock_latency_us\":{{\"p50\":{},\"p95\":{},\"p99\":{},\"max\":{},\"count\":{}}}",
+            latency.p50.as_micros(), latency.p95.as_micros(), latency.p99.as_micros(), latency.max.as_micros(), latency.count),
+        None => String::new()
+    };
+    let state = format!("{{\"version\":{},\"locked_size\":{},\"prewarm_size\":{},\"files\":[{}],\"locations\":[{}]{}}}\n",
+        STATE_VERSION, lock.current_size, lock.prewarm_size, files.join(","), locations.join(","), latency);
+    let tmp_file = format!("{}.tmp", state_file);
+    match fs::write(&tmp_file, state).and_then(|_| fs::rename(&tmp_file, state_file)).and_then(|_| fs::metadata(state_file)) {
+        Ok(file_data) => STATE_INODE.store(file_data.ino(), Ordering::SeqCst),
+        Err(err) => println!("Failed writing state to {}: {}", state_file, err)
+    }
+}
+
+/// The outcome of the startup pass as one JSON object, to summary_file or stderr for -
+fn write_summary(summary_file: &str, lock: &Lock) -> Result<(), String> {
+    let loaded = LOADED.read().unwrap();
+    let skipped: Vec<String> = lock.skipped.iter().map(|(reason, count)| format!("\"{}\":{}", reason, count)).collect();
+    let summary = format!("{{\"locked_size\":{},\"locked_files\":{},\"prewarm_size\":{},\"prewarmed_files\":{},\"max_total_size\":{},\"budget_limited\":{},\"skipped\":{{{}}}}}\n",
+        lock.current_size, loaded.iter().filter(|file| file.locked).count(), lock.prewarm_size, loaded.iter().filter(|file| !file.locked).count(),
+        lock.max_total_size, lock.budget_limited, skipped.join(","));
+    if summary_file == "-" {
+        eprint!("{}", summary);
+        return Ok(())
+    }
+    fs::write(summary_file, summary).map_err(|err| format!("Failed writing summary to {}: {}", summary_file, err))
+}
+
+/// Asks the instance on status_socket to exit through handoff, once this one holds its own locks.
+/// Both lock the same pages for a moment, the kernel keeps a page locked while any mapping of it is,
+/// so nothing is unlocked in between. The successor binds the socket and writes the state file after,
+/// the old instance only removes them if they are still its own.
+fn take_over(status_socket: &str) {
+    use std::os::unix::net::UnixStream;
+    let outcome = UnixStream::connect(status_socket).and_then(|mut stream| {
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        stream.write_all(b"handoff\n")?;
+        let mut response = String::new();
+        std::io::BufReader::new(&stream).read_line(&mut response)?;
+        Ok(response)
+    });
+    match outcome {
+        Ok(response) if response.trim() == "Handing off" => println!("Took over from the instance on {}", status_socket),
+        Ok(response) => println!("Warning: The instance on {} didn't hand off: {}", status_socket, response.trim()),
+        Err(err) => println!("Warning: Nothing to take over on {}: {}", status_socket, err)
+    }
+}
+
+extern "C" fn handle_shutdown(_: libc::c_int) {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+extern "C" fn handle_reload(_: libc::c_int) {
+    RELOAD.store(true, Ordering::SeqCst);
+}
+
+/// Reads the config file again and moves the loaded files over to what it selects.
+/// Files selected before and after keep their mapping, so there is no moment they are unlocked.
+fn reload_config(lock: &mut Lock) -> bool {
+    println!("Reloading {}", lock.config_file);
+    let mut new_lock = match read_config(&lock.config_file, lock.config_yaml) {
+        Ok(v) => v,
+        Err(err) => {
+            println!("Failed to reload, keeping the current config: {}", err);
+            return false
+        }
+    };
+    // Only measured during startup
+    new_lock.lock_latency = lock.lock_latency.take();
+    *lock = new_lock;
+    match find_files(lock, None) {
+        Ok(to_load) => {
+            sync_loaded(&to_load, lock);
+            print_summary(lock);
+            true
+        }
+        Err(err) => {
+            println!("Failed to find files: {}", err);
+            update_sizes(lock);
+            false
+        }
+    }
+}
+
+/// Scans the locations and the load table again, locking new files and ones that became stable since and
+/// unlocking ones that are gone or no longer match. Waits while files are unlocked for min_available,
+/// watch_memory locks those again on its own.
+fn rescan_files(lock: &mut Lock) -> bool {
+    if !lock.evicted.is_empty() {
+        return false
+    }
+    match find_files(lock, None) {
+        Ok(to_load) => {
+            if !sync_loaded(&to_load, lock) {
+                return false
+            }
+            print_summary(lock);
+            true
+        }
+        Err(err) => {
+            println!("Failed to find files: {}", err);
+            false
+        }
+    }
+}
+
+/// Remaps files with track_growth that grew since they were locked, as far as the budget allows
+fn extend_grown(lock: &mut Lock) -> bool {
+    let mut loaded = LOADED.write().unwrap();
+    let mut budget = Budget::from_loaded(&loaded, lock);
+    let mut extended = false;
+    for file in loaded.iter_mut().filter(|file| file.track_growth && file.locked) {
+        let Ok(file_data) = fs::metadata(&file.path) else {
+            continue
+        };
+        let new_size = lock_length(file_data.len(), lock) as usize;
+        let old_size = file.mmap.len();
+        if new_size <= old_size || new_size > lock.max_file_size {
+            continue;
+        }
+        if !budget.try_reserve(file.location, new_size - old_size, lock) {
+            println!("Not enough budget left to extend {}", file.path);
+            continue;
+        }
+        let Some(mmap) = map_file(&file.path, new_size, lock) else {
+            budget.release(file.location, new_size - old_size);
+            continue
+        };
+        // Lock the new mapping before the old one is dropped, so the start of the file stays locked
+        if let Err(err) = mmap.lock() {
+            println!("Failed to extend {}: {}", file.path, err);
+            record_failure(&file.path, format!("mlock: {}", err));
+            budget.release(file.location, new_size - old_size);
+            continue;
+        }
+        file.mmap = mmap;
+        lock.current_size += new_size - old_size;
+        extended = true;
+        println!("Extended {} to {}", file.path, bytes_to_size(new_size));
+    }
+    extended
+}
+
+fn record_failure(path: &str, reason: String) {
+    FAILURES.lock().unwrap().push((String::from(path), reason));
+}
+
+/// Sends the failures recorded since the last call to alert_webhook from another thread,
+/// retrying with backoff so a slow or unreachable endpoint never holds up locking
+#[cfg(feature = "webhook")]
+fn send_alerts(lock: &Lock) {
+    let failures = std::mem::take(&mut *FAILURES.lock().unwrap());
+    let Some(webhook) = lock.alert_webhook.clone() else {
+        return
+    };
+    if failures.is_empty() {
+        return
+    }
+    let host = fs::read_to_string("/proc/sys/kernel/hostname").unwrap_or_default();
+    let failures: Vec<String> = failures.iter().map(|(path, reason)| {
+        format!("{{\"path\":{},\"reason\":{}}}", json_string(path), json_string(reason))
+    }).collect();
+    let body = format!("{{\"host\":{},\"failures\":[{}]}}", json_string(host.trim()), failures.join(","));
+    std::thread::spawn(move || {
+        let mut delay = Duration::from_secs(1);
+        for attempt in 1..=5 {
+            match post_json(&webhook, &body) {
+                Ok(_) => return,
+                Err(err) if attempt == 5 => println!("Failed to send alert to {}: {}", webhook, err),
+                Err(_) => {
+                    std::thread::sleep(delay);
+                    delay *= 2;
+                }
+            }
+        }
+    });
+}
+
+#[cfg(not(feature = "webhook"))]
+fn send_alerts(_: &Lock) {
+    FAILURES.lock().unwrap().clear();
+}
+
+/// Minimal HTTP/1.1 POST to an http:// url, succeeds on a 2xx status
+#[cfg(feature = "webhook")]
+fn post_json(url: &str, body: &str) -> Result<(), String> {
+    use std::net::{TcpStream, ToSocketAddrs};
+    let Some(url) = url.strip_prefix("http://") else {
+        return Err("only http:// urls are supported".to_string())
+    };
+    let (host, path) = match url.find('/') {
+        Some(index) => (&url[..index], &url[index..]),
+        None => (url, "/")
+    };
+    let address = if host.contains(':') { host.to_string() } else { format!("{}:80", host) };
+    let address = address.to_socket_addrs().map_err(|err| err.to_string())?.next().ok_or("no address")?;
+    let timeout = Duration::from_secs(10);
+    let mut stream = TcpStream::connect_timeout(&address, timeout).map_err(|err| err.to_string())?;
+    let _ = stream.set_read_timeout(Some(timeout));
+    let _ = stream.set_write_timeout(Some(timeout));
+    let request = format!("POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path, host, body.len(), body);
+    stream.write_all(request.as_bytes()).map_err(|err| err.to_string())?;
+    let mut response = [0u8; 12];
+    stream.read_exact(&mut response).map_err(|err| err.to_string())?;
+    // "HTTP/1.1 200"
+    match response.get(9) {
+        Some(b'2') => Ok(()),
+        _ => Err(format!("server answered {}", String::from_utf8_lossy(&response[9..])))
+    }
+}
+
+/// Locks locked files again that have pages missing from memory,
+/// a file still missing pages after residency_retries checks in a row is given up on
+fn reconcile_residency(lock: &Lock) {
+    let mut loaded = LOADED.write().unwrap();
+    let page_size = page_size();
+    for file in loaded.iter_mut().filter(|file| file.locked && file.relock_attempts < lock.residency_retries) {
+        let pages = file.mmap.page_aligned_len(page_size)/page_size;
+        let resident = resident_pages(&file.mmap);
+        if resident >= pages {
+            file.relock_attempts = 0;
+            continue;
+        }
+        file.relock_attempts += 1;
+        println!("{} has {} of {} pages resident, locking it again", file.path, resident, pages);
+        // Segment by segment, Mapping::lock would unlock the segments before a failing one
+        for segment in &file.mmap.segments {
+            if let Err(err) = segment.lock() {
+                println!("Failed to lock {} again: {}", file.path, err);
+            }
+        }
+        if file.relock_attempts == lock.residency_retries && resident_pages(&file.mmap) < pages {
+            println!("Giving up on keeping {} resident after {} attempts", file.path, file.relock_attempts);
+            record_failure(&file.path, format!("not resident after {} attempts", file.relock_attempts));
+        }
+    }
+}
+
+/// The largest count of files from the start of files that doesn't end in the middle of an all_or_nothing set
+fn group_boundary(files: &[(String, FileInfo)], count: usize) -> usize {
+    let mut count = count;
+    while count > 0 && count < files.len() && files[count].1.all_or_nothing.is_some() && files[count].1.all_or_nothing == files[count-1].1.all_or_nothing {
+        count -= 1;
+    }
+    count
+}
+
+/// Unlocks files from the end when available memory is below min_available, until enough is freed to reach resume_available.
+/// Unlocked files go into evicted and are locked again in order once they fit above resume_available.
+fn watch_memory(lock: &mut Lock, sys: &mut System) -> bool {
+    let Some(min_available) = lock.min_available else {
+        return false
+    };
+    sys.refresh_memory_specifics(MemoryRefreshKind::new().with_ram());
+    let available = sys.available_memory() as usize;
+    if available < min_available {
+        let mut loaded = LOADED.write().unwrap();
+        let mut freed = 0;
+        let mut count = 0;
+        while available + freed < lock.resume_available {
+            let Some(file) = loaded.pop() else {
+                break
+            };
+            // The rest of an all_or_nothing set goes with it, from the last locked to the first
+            let mut files = vec![file];
+            if let Some(group) = files[0].all_or_nothing.clone() {
+                for index in (0..loaded.len()).rev() {
+                    if loaded[index].all_or_nothing.as_ref() == Some(&group) {
+                        files.push(loaded.remove(index));
+                    }
+                }
+            }
+            for file in files {
+                freed += file.mmap.len();
+                count += 1;
+                let info = FileInfo { size: file.mmap.len() as u64, inode: (0, 0), aliases: file.aliases, track_growth: file.track_growth, location: file.location, all_or_nothing: file.all_or_nothing, list: None };
+                // Kept in lock order, the last one unlocked is the first to be locked again
+                lock.evicted.insert(0, (file.path, info));
+            }
+        }
+        drop(loaded);
+        if count == 0 {
+            return false
+        }
+        update_sizes(lock);
+        println!("Available memory {} is below min_available {}, unlocked {} files ({})",
+            bytes_to_size(available), bytes_to_size(min_available), count, bytes_to_size(freed));
+        return true
+    }
+    // Locked again meanwhile by a reload, schedule window or add-location
+    let loaded: HashSet<String> = LOADED.read().unwrap().iter().map(|file| file.path.clone()).collect();
+    lock.evicted.retain(|file| !loaded.contains(&file.0));
+    let mut room = available.saturating_sub(lock.resume_available);
+    let fits = lock.evicted.iter().take_while(|file| {
+        if file.1.size as usize > room {
+            return false
+        }
+        room -= file.1.size as usize;
+        true
+    }).count();
+    let fits = group_boundary(&lock.evicted, fits);
+    if fits == 0 {
+        return false
+    }
+    let mut relock: Vec<(String, FileInfo)> = lock.evicted.drain(..fits).collect();
+    // Files that can't be locked yet wait in front of the rest
+    let mut left_out = fit_memlock_limit_running(&mut relock, lock);
+    left_out.splice(0..0, relock.drain(group_boundary(&relock, relock.len())..));
+    lock.evicted.splice(0..0, left_out);
+    if relock.is_empty() {
+        return false
+    }
+    lock_files_with(&relock, lock, |_| {});
+    update_sizes(lock);
+    println!("Memory recovered, locked {} files again, {} of memory locked", relock.len(), bytes_to_size(lock.current_size));
+    true
+}
+
+/// rescan_interval moved randomly within rescan_jitter either way,
+/// so instances started at the same time drift apart
+fn next_interval(lock: &Lock) -> Duration {
+    let random = RandomState::new().build_hasher().finish() as f64/u64::MAX as f64;
+    (lock.rescan_interval + lock.rescan_jitter.mul_f64(2.0*random)).saturating_sub(lock.rescan_jitter)
+}
+
+fn install_signal_handlers() {
+    unsafe {
+        libc::signal(libc::SIGTERM, handle_shutdown as extern "C" fn(libc::c_int) as libc::sighandler_t);
+        libc::signal(libc::SIGINT, handle_shutdown as extern "C" fn(libc::c_int) as libc::sighandler_t);
+        libc::signal(libc::SIGHUP, handle_reload as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+}
+
+fn daemon_run(lock: &mut Lock) {
+    write_state(lock);
+    // Bound once, a changed status_socket needs a restart
+    let status_socket = lock.status_socket.clone().and_then(|path| match serve_status(&path, lock.status_socket_mode, lock.status_socket_group) {
+        Ok(inode) => Some((path, inode)),
+        Err(err) => {
+            println!("Warning: {}", err);
+            None
+        }
+    });
+    let mut next_rescan = Instant::now() + next_interval(lock);
+    let mut next_residency_check = lock.residency_check_interval.map(|interval| Instant::now() + interval);
+    let mut sys = System::new();
+    let mut paused = false;
+    while !SHUTDOWN.load(Ordering::SeqCst) {
+        std::thread::sleep(Duration::from_secs(1));
+        // A reload asked for meanwhile stays pending, missed residency checks run right after resume
+        if PAUSED.load(Ordering::SeqCst) {
+            paused = true;
+            continue;
+        }
+        // Files may have been replaced during the maintenance, so a rescan runs right after resume
+        if std::mem::take(&mut paused) {
+            next_rescan = Instant::now();
+        }
+        let added = std::mem::take(&mut *ADDED_LOCATIONS.lock().unwrap());
+        for (path, reply) in added {
+            let outcome = add_location(lock, &path);
+            println!("{}", outcome);
+            let _ = reply.send(outcome);
+            write_state(lock);
+        }
+        if watch_memory(lock, &mut sys) {
+            write_state(lock);
+        }
+        if RELOAD.swap(false, Ordering::SeqCst) {
+            if reload_config(lock) {
+                write_state(lock);
+            }
+            next_residency_check = lock.residency_check_interval.map(|interval| Instant::now() + interval);
+        }
+        if let (Some(check), Some(interval)) = (next_residency_check, lock.residency_check_interval) {
+            if Instant::now() >= check {
+                reconcile_residency(lock);
+                next_residency_check = Some(Instant::now() + interval);
+            }
+        }
+        if Instant::now() < next_rescan {
+            continue;
+        }
+        next_rescan = Instant::now() + next_interval(lock);
+        let mut changed = false;
+        if lock.upgrade_locks && lock.prewarm_size > 0 {
+            changed |= upgrade_locks(lock);
+        }
+        // Entering or leaving a window scans again already
+        let window = (!lock.schedule.is_empty()).then(|| current_window(lock)).filter(|window| *window != lock.active_window);
+        match window {
+            Some(window) => changed |= apply_window(lock, window),
+            None => changed |= rescan_files(lock)
+        }
+        changed |= extend_grown(lock);
+        changed |= refresh_coherent(lock);
+        changed |= trim_for_overhead(lock);
+        if changed {
+            write_state(lock);
+        }
+        send_alerts(lock);
+    }
+    // A successor that took over may have replaced either by now
+    if let Some(state_file) = &lock.state_file {
+        if fs::metadata(state_file).is_ok_and(|v| v.ino() == STATE_INODE.load(Ordering::SeqCst)) {
+            let _ = fs::remove_file(state_file);
+        }
+    }
+    if let Some((status_socket, inode)) = status_socket {
+        if fs::symlink_metadata(&status_socket).is_ok_and(|v| v.ino() == inode) {
+            let _ = fs::remove_file(status_socket);
+        }
+    }
+}
+
+/// Scans path as another recursive location and locks the files from it that the load table matches, within the budget.
+/// The location is gone after a reload, which then unlocks its files too.
+fn add_location(lock: &mut Lock, path: &str) -> String {
+    let Ok(canonical) = fs::canonicalize(path) else {
+        return format!("Not adding location {}, it doesn't exist", path)
+    };
+    if !canonical.is_dir() {
+        return format!("Not adding location {}, it isn't a directory", path)
+    }
+    if let Some(location) = lock.locations.iter().find(|location| fs::canonicalize(&location.path).is_ok_and(|v| v == canonical)) {
+        return format!("Not adding location {}, it is the same as {}", path, location.path)
+    }
+    lock.locations.push(Location { path: String::from(path), recursive: true, ..Default::default() });
+    let index = lock.locations.len() - 1;
+    let to_load = match find_files(lock, None) {
+        Ok(v) => v,
+        Err(err) => {
+            lock.locations.pop();
+            return format!("Not adding location {}, failed to find files: {}", path, err)
+        }
+    };
+    let loaded: HashSet<String> = LOADED.read().unwrap().iter().map(|file| file.path.clone()).collect();
+    let mut added: Vec<(String, FileInfo)> = to_load.into_iter().filter(|file| file.1.location == Some(index) && !loaded.contains(&file.0)).collect();
+    if let Err(err) = fit_memlock_limit(&mut added, lock) {
+        lock.locations.pop();
+        return format!("Not adding location {}: {}", path, err)
     }
+    let before = lock.current_size;
+    lock_files_with(&added, lock, |_| {});
+    update_sizes(lock);
+    report_permission_denied();
+    format!("Added location {}, locked {} of {} matching files ({}), {} of memory locked", path,
+        LOADED.read().unwrap().iter().filter(|file| file.location == Some(index) && file.locked).count(), added.len(),
+        bytes_to_size(lock.current_size.saturating_sub(before)), bytes_to_size(lock.current_size))
+}
+
+/// Goes through the files the way locking would, without mapping them
+fn explain(lock: &Lock) -> Result<(), String> {
+    const SHOWN: usize = 5;
+    let to_load = find_files(lock, None)?;
+    let mut budget = Budget::new(lock);
+    // Replayed alongside the selection to show the running total and why a file was left out
+    let mut selected = budget.clone().select(&to_load, lock).into_iter().peekable();
+    let mut dropped: Vec<(&(String, FileInfo), String)> = Vec::new();
+    for file in to_load.iter() {
+        let size = file.1.size as usize;
+        if selected.next_if(|selected| std::ptr::eq(*selected, file)).is_some() {
+            budget.add(file.1.location, size);
+            println!("{} - {} ({} total)", file.0, bytes_to_size(size), bytes_to_size(budget.total));
+        } else if budget.total + size > lock.max_total_size {
+            dropped.push((file, String::from("max_total_size")));
+        } else {
+            let location = file.1.location.map(|v| lock.locations[v].path.as_str()).unwrap_or_default();
+            dropped.push((file, format!("max_total_size of {}", location)));
+        }
+    }
+    println!("--- cutline: {} of {} files fit, {} of {} ---", to_load.len() - dropped.len(), to_load.len(),
+        bytes_to_size(budget.total), bytes_to_size(lock.max_total_size));
+    for (file, reason) in dropped.iter().take(SHOWN) {
+        println!("{} - {} (over {})", file.0, bytes_to_size(file.1.size as usize), reason);
+    }
+    if dropped.len() > SHOWN {
+        println!("{} more files didn't fit", dropped.len() - SHOWN);
+    }
+    println!("--- under memory pressure files are unlocked from the cutline up ---");
     Ok(())
 }
 
-fn daemon_run() {
-    loop {
-        std::thread::sleep(std::time::Duration::from_secs(30));
+fn list_candidates(lock: &Lock) -> Result<(), String> {
+    let candidates = Mutex::new(Vec::new());
+    find_files(lock, Some(&candidates))?;
+    let candidates = candidates.into_inner().unwrap();
+    // Counted in the order sources first matched something
+    let mut counts: Vec<(&str, usize)> = Vec::new();
+    for (source, path) in candidates.iter() {
+        println!("{} ({})", path, source);
+        match counts.iter_mut().find(|count| count.0 == source) {
+            Some(count) => count.1 += 1,
+            None => counts.push((source, 1))
+        }
+    }
+    for (source, count) in counts {
+        println!("{} files matched {}", count, source);
+    }
+    let unique: HashSet<&str> = candidates.iter().map(|candidate| candidate.1.as_str()).collect();
+    println!("{} candidate files", unique.len());
+    Ok(())
+}
+
+/// Files locked and memory locked or prewarmed from each location
+fn location_usage(loaded: &[LoadedFile], lock: &Lock) -> Vec<(usize, usize)> {
+    let mut usage = vec![(0, 0); lock.locations.len()];
+    for file in loaded {
+        if let Some(location) = file.location {
+            usage[location].0 += file.locked as usize;
+            usage[location].1 += file.mmap.len();
+        }
+    }
+    usage
+}
+
+fn print_location_stats(lock: &Lock) {
+    let loaded = LOADED.read().unwrap();
+    for (location, (locked, size)) in lock.locations.iter().zip(location_usage(&loaded, lock)) {
+        println!("{} - {} scanned, {} matched, {} locked, {} of memory", location.path,
+            location.scanned.load(Ordering::Relaxed), location.matched.load(Ordering::Relaxed), locked, bytes_to_size(size));
     }
 }
 
 fn daemon_usage() {
-    unsafe {
-        let loaded = LOADED.read().expect("Failed to get loaded files");
-        for file in loaded.iter() {
-            println!("{} - {}", file.0.clone(), bytes_to_size(file.1.len()));
+    print!("{}", usage_report());
+}
+
+/// The loaded files grouped by the filesystem they are on, with subtotals
+fn daemon_usage_by_fs() {
+    let mounts = mounts();
+    let loaded = LOADED.read().expect("Failed to get loaded files");
+    let mut groups: Vec<(Option<&Mount>, Vec<&LoadedFile>)> = Vec::new();
+    for file in loaded.iter() {
+        let mount = mount_of(&file.path, &mounts);
+        match groups.iter_mut().find(|group| group.0.map(|v| &v.mount_point) == mount.map(|v| &v.mount_point)) {
+            Some(group) => group.1.push(file),
+            None => groups.push((mount, vec![file]))
+        }
+    }
+    groups.sort_by(|a, b| a.0.map(|v| &v.mount_point).cmp(&b.0.map(|v| &v.mount_point)));
+    for (mount, files) in groups {
+        let size: usize = files.iter().map(|file| file.mmap.len()).sum();
+        match mount {
+            Some(mount) => println!("{} ({} on {}) - {} files, {}", mount.mount_point, mount.fs_type, mount.source, files.len(), bytes_to_size(size)),
+            None => println!("Unknown filesystem - {} files, {}", files.len(), bytes_to_size(size))
+        }
+        for file in files {
+            println!("    {} - {}{}", file.path, bytes_to_size(file.mmap.len()), if file.locked { "" } else { " (prewarmed)" });
+        }
+    }
+}
+
+/// Groups of locked files with the same content, hardlinks already share their pages and aren't counted.
+/// Files over DUPES_MAX_SIZE aren't read, comparing them could mean going through gigabytes.
+fn show_dupes() {
+    const DUPES_MAX_SIZE: usize = 64*MIB;
+    let loaded = LOADED.read().expect("Failed to get loaded files");
+    let state = RandomState::new();
+    let mut inodes = HashSet::new();
+    let mut skipped = 0;
+    let mut buckets: HashMap<(usize, u64), Vec<&LoadedFile>> = HashMap::new();
+    for file in loaded.iter().filter(|file| file.locked && file.mmap.len() > 0) {
+        if file.mmap.len() > DUPES_MAX_SIZE {
+            skipped += 1;
+            continue;
+        }
+        if fs::metadata(&file.path).is_ok_and(|v| !inodes.insert((v.dev(), v.ino()))) {
+            continue;
+        }
+        let mut hasher = state.build_hasher();
+        for segment in file.mmap.segments.iter() {
+            hasher.write(segment);
+        }
+        buckets.entry((file.mmap.len(), hasher.finish())).or_default().push(file);
+    }
+    // Confirmed byte for byte, a hash collision doesn't make files identical
+    let mut groups: Vec<Vec<&LoadedFile>> = Vec::new();
+    for files in buckets.into_values() {
+        let mut bucket_groups: Vec<Vec<&LoadedFile>> = Vec::new();
+        for file in files {
+            let same = |other: &&LoadedFile| other.mmap.segments.iter().zip(file.mmap.segments.iter()).all(|(a, b)| a[..] == b[..]);
+            match bucket_groups.iter_mut().find(|group| same(&group[0])) {
+                Some(group) => group.push(file),
+                None => bucket_groups.push(vec![file])
+            }
+        }
+        groups.extend(bucket_groups.into_iter().filter(|group| group.len() > 1));
+    }
+    groups.sort_by_key(|group| std::cmp::Reverse((group.len()-1)*group[0].mmap.len()));
+    let mut saved = 0;
+    for group in groups.iter() {
+        let size = group[0].mmap.len();
+        saved += (group.len()-1)*size;
+        println!("Identical content - {} files of {}, {} could be saved", group.len(), bytes_to_size(size), bytes_to_size((group.len()-1)*size));
+        for file in group {
+            println!("    {}", file.path);
+        }
+    }
+    println!("{} groups of locked files with identical content, locking one copy of each would save {}", groups.len(), bytes_to_size(saved));
+    if skipped > 0 {
+        println!("{} files over {} weren't compared", skipped, bytes_to_size(DUPES_MAX_SIZE));
+    }
+}
+
+/// The per-file listing of --usage, also sent to clients of status_socket
+fn usage_report() -> String {
+    let mut report = String::new();
+    let loaded = LOADED.read().expect("Failed to get loaded files");
+    let mut logical = 0;
+    let mut on_disk = 0;
+    for file in loaded.iter() {
+        let mut notes = Vec::new();
+        if !file.locked {
+            notes.push(String::from("prewarmed"));
+        }
+        // Allocated blocks, less than the size when the filesystem compresses it (zfs does, btrfs reports
+        // uncompressed blocks) or the file is sparse. Locked is always the size, pages hold the data uncompressed.
+        if let Ok(file_data) = fs::metadata(&file.path) {
+            logical += file_data.len();
+            on_disk += file_data.blocks()*512;
+            if file_data.blocks()*512 < file_data.len() {
+                notes.push(format!("{} on disk", bytes_to_size(file_data.blocks() as usize*512)));
+            }
+        }
+        if !file.aliases.is_empty() {
+            notes.push(format!("also {}", file.aliases.join(", ")));
+        }
+        if notes.is_empty() {
+            report.push_str(&format!("{} - {}\n", file.path, bytes_to_size(file.mmap.len())));
+        } else {
+            report.push_str(&format!("{} - {} ({})\n", file.path, bytes_to_size(file.mmap.len()), notes.join("; ")));
+        }
+    }
+    // The last page of every file is only partly used, with many small files that adds up
+    let size: usize = loaded.iter().map(|file| file.mmap.len()).sum();
+    let paged: usize = loaded.iter().map(|file| file.mmap.page_aligned_len(page_size())).sum();
+    report.push_str(&format!("{} of file data in {} of pages, {} lost to page rounding\n", bytes_to_size(size), bytes_to_size(paged), bytes_to_size(paged - size)));
+    if on_disk < logical {
+        report.push_str(&format!("The files are {} but take up {} on disk, du shows the latter while the budget counts what is locked\n",
+            bytes_to_size(logical as usize), bytes_to_size(on_disk as usize)));
+    }
+    // Every file is its own mapping, the kernel can't merge mappings of different files
+    if let (Some(vmas), Some(max_map_count)) = (count_vmas(), max_map_count()) {
+        report.push_str(&format!("{} file mappings, {} of vm.max_map_count {} mappings in use\n", loaded.iter().map(|file| file.mmap.segments.len()).sum::<usize>(), vmas, max_map_count));
+    }
+    report
+}
+
+/// Looks up a group by name for status_socket_group
+fn group_id(name: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    let group = unsafe { libc::getgrnam(name.as_ptr()) };
+    (!group.is_null()).then(|| unsafe { (*group).gr_gid })
+}
+
+/// Applies status_socket_mode and status_socket_group, before anything is served
+fn set_socket_access(path: &str, mode: Option<u32>, group: Option<u32>) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    if let Some(group) = group {
+        std::os::unix::fs::chown(path, None, Some(group))?;
+    }
+    if let Some(mode) = mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
+
+/// Answers "usage" on the socket with the --usage listing and how much is locked,
+/// the loaded files are only read so clients can't disturb the mappings.
+/// "pause" freezes the locked set until "resume", for storage maintenance.
+/// "eviction" lists the loaded files in the order they would be unlocked.
+/// "add-location <path>" locks the files under path that the load table matches, until the next reload.
+/// "handoff" makes the daemon exit, for a successor started with --take-over.
+fn serve_status(path: &str, mode: Option<u32>, group: Option<u32>) -> Result<u64, String> {
+    // Left behind by an instance that didn't shut down cleanly
+    if fs::symlink_metadata(path).is_ok_and(|v| v.file_type().is_socket()) {
+        let _ = fs::remove_file(path);
+    }
+    let listener = match UnixListener::bind(path) {
+        Ok(v) => v,
+        Err(err) => return Err(format!("Failed to bind status_socket {}: {}", path, err))
+    };
+    if let Err(err) = set_socket_access(path, mode, group) {
+        let _ = fs::remove_file(path);
+        return Err(format!("Failed to set the permissions of status_socket {}: {}", path, err))
+    }
+    let inode = fs::symlink_metadata(path).map(|v| v.ino()).unwrap_or(0);
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else {
+                continue
+            };
+            let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+            let _ = stream.set_write_timeout(Some(Duration::from_secs(5)));
+            let mut request = String::new();
+            // A client gone mid-request or mid-response only loses its own answer
+            if std::io::BufReader::new(&stream).read_line(&mut request).is_err() {
+                continue;
+            }
+            let response = match request.trim() {
+                "usage" | "" => {
+                    let locked: usize = LOADED.read().unwrap().iter().filter(|file| file.locked).map(|file| file.mmap.len()).sum();
+                    let paused = if PAUSED.load(Ordering::SeqCst) { ", paused" } else { "" };
+                    format!("{}{} of memory locked{}\n", usage_report(), bytes_to_size(locked), paused)
+                }
+                // A successor holds the same locks by now, see take_over
+                "handoff" => {
+                    println!("Handing off to a successor, exiting");
+                    SHUTDOWN.store(true, Ordering::SeqCst);
+                    String::from("Handing off\n")
+                }
+                "pause" => if PAUSED.swap(true, Ordering::SeqCst) {
+                    String::from("Already paused\n")
+                } else {
+                    println!("Paused, rescans, reloads and eviction are suspended until resume");
+                    String::from("Paused\n")
+                },
+                "resume" => if PAUSED.swap(false, Ordering::SeqCst) {
+                    println!("Resumed, rescanning");
+                    String::from("Resumed\n")
+                } else {
+                    String::from("Not paused\n")
+                },
+                // The order files are unlocked in under memory pressure or when the budget shrinks
+                "eviction" => {
+                    let loaded = LOADED.read().unwrap();
+                    loaded.iter().rev().enumerate().map(|(index, file)| format!("{}. {} - {}\n", index + 1, file.path, bytes_to_size(file.mmap.len()))).collect()
+                }
+                request if request.starts_with("add-location ") => {
+                    let path = request["add-location ".len()..].trim().to_string();
+                    if PAUSED.load(Ordering::SeqCst) {
+                        String::from("Paused, resume first\n")
+                    } else {
+                        // Scanned and locked by daemon_run, which has the config
+                        let (sender, receiver) = std::sync::mpsc::channel();
+                        ADDED_LOCATIONS.lock().unwrap().push((path, sender));
+                        match receiver.recv_timeout(Duration::from_secs(60)) {
+                            Ok(outcome) => format!("{}\n", outcome),
+                            Err(_) => String::from("Still locking, see the log for the outcome\n")
+                        }
+                    }
+                }
+                request => format!("Unknown request {}, only usage, eviction, pause, resume, add-location and handoff are supported\n", request)
+            };
+            let _ = stream.write_all(response.as_bytes());
+        }
+    });
+    Ok(inode)
+}
+
+struct Latency {
+    p50: Duration,
+    p95: Duration,
+    p99: Duration,
+    max: Duration,
+    count: usize
+}
+
+/// Nearest-rank percentiles, None without any durations
+fn latency_percentiles(mut latencies: Vec<Duration>) -> Option<Latency> {
+    if latencies.is_empty() {
+        return None
+    }
+    latencies.sort();
+    let percentile = |p: usize| latencies[(latencies.len()*p).div_ceil(100)-1];
+    Some(Latency { p50: percentile(50), p95: percentile(95), p99: percentile(99), max: latencies[latencies.len()-1], count: latencies.len() })
+}
+
+/// Memory mappings of this process, from /proc/self/maps
+fn count_vmas() -> Option<usize> {
+    Some(fs::read_to_string("/proc/self/maps").ok()?.lines().count())
+}
+
+/// Cuts to_load where its mappings would take this process past max_map_fraction of vm.max_map_count,
+/// mmap fails for every file after that. With raise_max_map_count the limit is raised instead, if permitted.
+fn fit_map_count(to_load: &mut Vec<(String, FileInfo)>, lock: &Lock) {
+    let (Some(vmas), Some(max_map_count)) = (count_vmas(), max_map_count()) else {
+        return
+    };
+    let mut selected = Budget::from_loaded(&LOADED.read().unwrap(), lock).select(to_load, lock).into_iter().peekable();
+    let mut needed = 0;
+    let mut ends = Vec::with_capacity(to_load.len());
+    for file in to_load.iter() {
+        let size = file.1.size as usize;
+        if selected.next_if(|selected| std::ptr::eq(*selected, file)).is_some() {
+            needed += if lock.max_mapping_size == 0 { 1 } else { size.div_ceil(lock.max_mapping_size).max(1) };
+        }
+        ends.push(needed);
+    }
+    let allowed = ((max_map_count as f64*lock.max_map_fraction) as usize).saturating_sub(vmas);
+    if needed <= allowed {
+        return
+    }
+    if lock.raise_max_map_count {
+        let raised = ((vmas + needed) as f64/lock.max_map_fraction).ceil() as usize;
+        match fs::write("/proc/sys/vm/max_map_count", raised.to_string()) {
+            Ok(()) => {
+                println!("Raised vm.max_map_count from {} to {} for {} more mappings", max_map_count, raised, needed);
+                return
+            }
+            Err(err) => println!("Failed to raise vm.max_map_count to {}: {}", raised, err)
         }
     }
+    let keep = ends.iter().position(|end| *end > allowed).unwrap_or(to_load.len());
+    println!("Warning: only locking {} of {} files, {} more mappings would be past {} of vm.max_map_count {} with {} already in use",
+        keep, to_load.len(), needed, vmas + allowed, max_map_count, vmas);
+    to_load.truncate(keep);
+}
+
+/// Checks the selection against RLIMIT_MEMLOCK before locking anything, instead of finding out
+/// when mlock starts failing. Without CAP_IPC_LOCK the kernel holds locked pages to the limit.
+fn fit_memlock_limit(to_load: &mut Vec<(String, FileInfo)>, lock: &Lock) -> Result<(), String> {
+    let Some(limit) = memlock_limit() else {
+        return Ok(())
+    };
+    let page_size = page_size();
+    let mut selected = Budget::from_loaded(&LOADED.read().unwrap(), lock).select(to_load, lock).into_iter().peekable();
+    let mut needed = 0;
+    let mut ends = Vec::with_capacity(to_load.len());
+    for file in to_load.iter() {
+        let size = file.1.size as usize;
+        if selected.next_if(|selected| std::ptr::eq(*selected, file)).is_some() {
+            needed += size.div_ceil(page_size)*page_size;
+        }
+        ends.push(needed);
+    }
+    let locked = read_vmlck().unwrap_or(0);
+    let allowed = limit.saturating_sub(locked);
+    if needed <= allowed {
+        return Ok(())
+    }
+    let message = format!("locking {} more with {} already locked goes past RLIMIT_MEMLOCK of {}", bytes_to_size(needed), bytes_to_size(locked), bytes_to_size(limit));
+    match lock.memlock_limit_policy {
+        MemlockLimitPolicy::Warn => println!("Warning: {}, files past it will fail to lock", message),
+        MemlockLimitPolicy::Truncate => {
+            let keep = ends.iter().position(|end| *end > allowed).unwrap_or(to_load.len());
+            println!("Warning: only locking {} of {} files, {}", keep, to_load.len(), message);
+            to_load.truncate(keep);
+        }
+        MemlockLimitPolicy::Error => return Err(format!("Not locking anything, {}", message))
+    }
+    Ok(())
+}
+
+/// fit_memlock_limit for the running daemon, where memlock_limit_policy error leaves the files out instead of exiting.
+/// Returns the files that were left out.
+fn fit_memlock_limit_running(to_load: &mut Vec<(String, FileInfo)>, lock: &Lock) -> Vec<(String, FileInfo)> {
+    let all = to_load.clone();
+    if let Err(err) = fit_memlock_limit(to_load, lock) {
+        println!("Warning: {}", err);
+        to_load.clear();
+    }
+    all.into_iter().skip(to_load.len()).collect()
+}
+
+/// Soft RLIMIT_MEMLOCK in bytes, None when it is unlimited or doesn't apply because of CAP_IPC_LOCK
+fn memlock_limit() -> Option<usize> {
+    const CAP_IPC_LOCK: u32 = 14;
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let cap_eff = status.lines().find_map(|line| line.strip_prefix("CapEff:")).and_then(|v| u64::from_str_radix(v.trim(), 16).ok())?;
+    if cap_eff & (1 << CAP_IPC_LOCK) != 0 {
+        return None
+    }
+    let mut rlimit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    if unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut rlimit) } != 0 || rlimit.rlim_cur == libc::RLIM_INFINITY {
+        return None
+    }
+    Some(rlimit.rlim_cur as usize)
+}
+
+fn max_map_count() -> Option<usize> {
+    fs::read_to_string("/proc/sys/vm/max_map_count").ok()?.trim().parse::<usize>().ok()
+}
+
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// A field of /proc/self/status in bytes
+fn read_status(name: &str) -> Option<usize> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.strip_prefix(name).is_some_and(|v| v.starts_with(':')))?;
+    let kib = line[name.len()+1..].trim().trim_end_matches("kB").trim().parse::<usize>().ok()?;
+    Some(kib*KIB)
+}
+
+/// VmLck from /proc/self/status in bytes
+fn read_vmlck() -> Option<usize> {
+    read_status("VmLck")
+}
+
+/// Memory of prelockd itself, its heap (the loaded files, compiled patterns) and the page tables of the mappings.
+/// Locked file pages are in RssFile, not in these.
+fn read_overhead() -> Option<usize> {
+    Some(read_status("RssAnon")? + read_status("VmPTE")?)
+}
+
+/// Unlocks files from the end while prelockd's own memory and the files together are over max_total_size,
+/// with count_overhead. They stay unlocked until a reload.
+fn trim_for_overhead(lock: &mut Lock) -> bool {
+    if !lock.count_overhead {
+        return false
+    }
+    let mut loaded = LOADED.write().unwrap();
+    let mut over = Budget::from_loaded(&loaded, lock).total.saturating_sub(lock.max_total_size);
+    if over == 0 {
+        return false
+    }
+    let mut count = 0;
+    let mut freed = 0;
+    while over > 0 {
+        let Some(file) = loaded.pop() else {
+            break
+        };
+        count += 1;
+        freed += file.mmap.len();
+        over = over.saturating_sub(file.mmap.len());
+    }
+    drop(loaded);
+    update_sizes(lock);
+    println!("prelockd takes up {} itself, unlocked {} files ({}) to stay within max_total_size",
+        bytes_to_size(read_overhead().unwrap_or(0)), count, bytes_to_size(freed));
+    count > 0
+}
+
+/// Fields of /proc/meminfo in bytes, in the order of names
+fn read_meminfo<const N: usize>(names: [&str; N]) -> Option<[usize; N]> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    let mut values = [0; N];
+    for (value, name) in values.iter_mut().zip(names) {
+        let line = meminfo.lines().find(|line| line.strip_prefix(name).is_some_and(|v| v.starts_with(':')))?;
+        *value = line[name.len()+1..].trim().trim_end_matches("kB").trim().parse::<usize>().ok()?*KIB;
+    }
+    Some(values)
+}
+
+/// CommitLimit and Committed_AS from /proc/meminfo in bytes
+fn read_commit() -> Option<(usize, usize)> {
+    let [commit_limit, committed] = read_meminfo(["CommitLimit", "Committed_AS"])?;
+    Some((commit_limit, committed))
+}
+
+/// Total memory from sysinfo, or MemTotal in /proc/meminfo when sysinfo hangs or comes back empty
+fn total_memory() -> Result<usize, String> {
+    const TIMEOUT: Duration = Duration::from_secs(2);
+    let (sender, receiver) = std::sync::mpsc::channel();
+    // Left running if it hangs, it can't hold anything up once it's detached
+    std::thread::spawn(move || {
+        let sys = System::new_with_specifics(RefreshKind::new().with_memory(MemoryRefreshKind::new().with_ram()));
+        let _ = sender.send(sys.total_memory() as usize);
+    });
+    let reason = match receiver.recv_timeout(TIMEOUT) {
+        Ok(total) if total > 0 => return Ok(total),
+        Ok(_) => String::from("sysinfo reported no memory"),
+        Err(_) => format!("sysinfo took longer than {}s", TIMEOUT.as_secs())
+    };
+    match read_meminfo(["MemTotal"]) {
+        Some([total]) if total > 0 => {
+            println!("{}, using MemTotal from /proc/meminfo", reason);
+            Ok(total)
+        }
+        _ => Err(format!("{} and /proc/meminfo couldn't be read, unable to get the memory size", reason))
+    }
+}
+
+/// Warns when locking size on top of what's committed gets within 10% of CommitLimit,
+/// with vm.overcommit_memory = 2 the kernel starts refusing allocations past it
+fn check_commit(size: usize) {
+    let Some((commit_limit, committed)) = read_commit() else {
+        return
+    };
+    if (committed+size)*10 >= commit_limit*9 {
+        let strict = fs::read_to_string("/proc/sys/vm/overcommit_memory").is_ok_and(|v| v.trim() == "2");
+        println!("Warning: locking {} with {} committed gets close to the commit limit of {}{}", bytes_to_size(size), bytes_to_size(committed),
+            bytes_to_size(commit_limit), if strict { ", allocations elsewhere may fail" } else { "" });
+    }
+}
+
+fn verify_accounting(lock: &Lock) -> Result<(), String> {
+    let Some(vmlck) = read_vmlck() else {
+        return Err("Unable to read VmLck from /proc/self/status".to_string())
+    };
+    // The kernel locks whole pages
+    let page_size = page_size();
+    let expected: usize = LOADED.read().unwrap().iter().filter(|file| file.locked).map(|file| file.mmap.page_aligned_len(page_size)).sum();
+    println!("Accounted {} ({} bytes, {} page aligned), kernel reports {} bytes locked", bytes_to_size(lock.current_size), lock.current_size, expected, vmlck);
+    if vmlck.abs_diff(expected) > page_size {
+        return Err(format!("Accounting is off by {} bytes", vmlck.abs_diff(expected)))
+    }
+    println!("Accounting matches");
+    Ok(())
 }
 
 fn main() -> Result<(), String> {
     let args = Args::parse();
-    let config_file = args.config.unwrap_or(String::from("/etc/prelockd-rs.toml"));
-    daemon_setup(config_file.as_str())?;
-    if args.usage {
-        daemon_usage();
+    let config_file = args.config.unwrap_or(String::from(DEFAULT_CONFIG));
+    let yaml = config_is_yaml(&config_file, args.config_format.as_deref())?;
+    TRACE_SCAN.store(args.trace_scan, Ordering::Relaxed);
+    if args.list_candidates {
+        return list_candidates(&read_config(config_file.as_str(), yaml)?)
+    }
+    if args.explain {
+        return explain(&read_config(config_file.as_str(), yaml)?)
+    }
+    // Before anything else of the daemon, a SIGHUP from ExecReload while the config is read or during the
+    // initial pass is a reload once it is done; the listings above stay interruptible with the default handlers
+    install_signal_handlers();
+    if let Some(format) = args.export.as_deref().filter(|format| *format != "vmtouch") {
+        return Err(format!("--export only supports vmtouch, not {}", format))
+    }
+    // Keeps the startup log out of what is exported
+    let stdout = args.export.is_some().then(|| unsafe {
+        let stdout = libc::dup(libc::STDOUT_FILENO);
+        libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO);
+        stdout
+    });
+    let startup_delay = match args.startup_delay.as_deref().map(time_to_duration) {
+        Some(Some(v)) => Some(v),
+        Some(None) => return Err(format!("--startup-delay {} is invalid", args.startup_delay.unwrap_or_default())),
+        None => None
+    };
+    let baseline: Mutex<(usize, usize)> = Mutex::new((0, 0));
+    let mut lock = daemon_setup(config_file.as_str(), yaml, args.resume, args.warm_from_residency.as_deref(), startup_delay, |event| {
+        if args.events {
+            println!("{} {} {} {}", if event.locked { "locked" } else { "prewarmed" }, event.size, event.total, event.path);
+        }
+        if args.log_locked && event.locked {
+            println!("Locked {} - {}, {} total", event.path, bytes_to_size(event.size), bytes_to_size(event.total));
+        }
+        if args.report_baseline {
+            println!("{} - {} pages already cached, {} read in", event.path, event.cached, event.pages - event.cached);
+            let mut baseline = baseline.lock().unwrap();
+            baseline.0 += event.cached;
+            baseline.1 += event.pages;
+        }
+    })?;
+    if let Some(status_socket) = args.take_over.as_deref().filter(|_| !SHUTDOWN.load(Ordering::SeqCst)) {
+        take_over(status_socket);
+    }
+    if let Some(summary_file) = &args.summary_json {
+        write_summary(summary_file, &lock)?;
+    }
+    if args.report_baseline {
+        let (cached, pages) = baseline.into_inner().unwrap();
+        if pages > 0 {
+            println!("{} of {} pages were already cached ({:.1}%), {} read in", cached, pages, cached as f64*100.0/pages as f64, pages - cached);
+        }
+    }
+    if let Some(stdout) = stdout {
+        let _ = std::io::stdout().flush();
+        unsafe {
+            libc::dup2(stdout, libc::STDOUT_FILENO);
+            libc::close(stdout);
+        }
+        for file in LOADED.read().unwrap().iter().filter(|file| file.locked) {
+            println!("{}", file.path);
+        }
+    } else if args.verify_accounting {
+        verify_accounting(&lock)?;
     } else {
-        daemon_run();
+        if args.usage && args.by_fs {
+            daemon_usage_by_fs();
+        } else if args.usage {
+            daemon_usage();
+        }
+        if args.show_dupes {
+            show_dupes();
+        }
+        if !args.once {
+            daemon_run(&mut lock);
+        }
+        if let Some(snapshot_file) = &args.save_residency {
+            save_residency(snapshot_file, &lock)?;
+        }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MEMORY: usize = 8*GIB;
+
+    /// A Lock from a minimal config, tests set the fields they need on it. The config file is named
+    /// after the test since tests run in parallel.
+    fn test_lock(name: &str) -> Lock {
+        let config_file = std::env::temp_dir().join(format!("prelockd-rs-{}-{}.toml", name, std::process::id()));
+        fs::write(&config_file, "[lock]\nmax_file_size = \"20m\"\nmax_total_size = \"10%\"\nsorting_method = \"fl\"\nlocations = []\n[load]\nfiles = []\nlists = []\n").unwrap();
+        let lock = read_config(config_file.to_str().unwrap(), false);
+        let _ = fs::remove_file(&config_file);
+        lock.unwrap()
+    }
+
+    #[test]
+    fn size_to_bytes_suffixes() {
+        assert_eq!(size_to_bytes("4096", MEMORY), Some(4096));
+        assert_eq!(size_to_bytes("4k", MEMORY), Some(4*KIB));
+        assert_eq!(size_to_bytes("20m", MEMORY), Some(20*MIB));
+        assert_eq!(size_to_bytes("2g", MEMORY), Some(2*GIB));
+        assert_eq!(size_to_bytes(" 2G ", MEMORY), Some(2*GIB));
+    }
+
+    #[test]
+    fn size_to_bytes_fractions_of_memory() {
+        assert_eq!(size_to_bytes("10%", MEMORY), Some(MEMORY/10));
+        assert_eq!(size_to_bytes("100%", MEMORY), Some(MEMORY));
+        assert_eq!(size_to_bytes("0%", MEMORY), Some(0));
+        assert_eq!(size_to_bytes("half", MEMORY), Some(MEMORY/2));
+        assert_eq!(size_to_bytes("third", MEMORY), Some(MEMORY/3));
+        assert_eq!(size_to_bytes("Quarter", MEMORY), Some(MEMORY/4));
+    }
+
+    #[test]
+    fn size_to_bytes_min_max() {
+        assert_eq!(size_to_bytes("min(2g, 10%)", MEMORY), Some(MEMORY/10));
+        assert_eq!(size_to_bytes("max(2g, 10%)", MEMORY), Some(2*GIB));
+        assert_eq!(size_to_bytes("min(1g, max(512m, quarter))", MEMORY), Some(GIB));
+        assert_eq!(size_to_bytes("max(4k)", MEMORY), Some(4*KIB));
+        assert_eq!(size_to_bytes("min(2g, abc)", MEMORY), None);
+    }
+
+    #[test]
+    fn size_to_bytes_malformed() {
+        assert_eq!(size_to_bytes("abc", MEMORY), None);
+        assert_eq!(size_to_bytes("10x", MEMORY), None);
+        assert_eq!(size_to_bytes("", MEMORY), None);
+        assert_eq!(size_to_bytes("-1m", MEMORY), None);
+        assert_eq!(size_to_bytes("1.5g", MEMORY), None);
+        assert_eq!(size_to_bytes("min(2g", MEMORY), None);
+        assert_eq!(size_to_bytes("99999999999999999999g", MEMORY), None);
+        assert_eq!(size_to_bytes("18446744073709551615g", MEMORY), None);
+    }
+
+    #[test]
+    fn next_interval_within_jitter() {
+        let mut lock = test_lock("next_interval");
+        lock.rescan_interval = Duration::from_secs(30);
+        lock.rescan_jitter = Duration::ZERO;
+        assert_eq!(next_interval(&lock), Duration::from_secs(30));
+        lock.rescan_jitter = Duration::from_secs(3);
+        for _ in 0..100 {
+            let interval = next_interval(&lock);
+            assert!(interval >= Duration::from_secs(27) && interval <= Duration::from_secs(33), "{:?}", interval);
+        }
+        // More jitter than interval can't go below zero
+        lock.rescan_jitter = Duration::from_secs(60);
+        for _ in 0..100 {
+            assert!(next_interval(&lock) <= Duration::from_secs(90));
+        }
+    }
+
+    #[test]
+    fn lock_length_fraction() {
+        let mut lock = test_lock("lock_length");
+        assert_eq!(lock_length(1001, &lock), 1001);
+        lock.lock_fraction = 0.5;
+        assert_eq!(lock_length(1000, &lock), 500);
+        // Rounded up, so a non-empty file never locks nothing
+        assert_eq!(lock_length(1001, &lock), 501);
+        assert_eq!(lock_length(1, &lock), 1);
+        assert_eq!(lock_length(0, &lock), 0);
+    }
+
+    #[test]
+    fn detect_file_type_from_header() {
+        let dir = std::env::temp_dir().join(format!("prelockd-rs-detect-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // e_ident with EI_DATA at byte 5, 1 for little and 2 for big endian, then e_type
+        let elf = |data: u8, e_type: [u8; 2]| {
+            let mut header = vec![0x7f, b'E', b'L', b'F', 2, data];
+            header.resize(16, 0);
+            header.extend(e_type);
+            header
+        };
+        let cases: [(&str, Vec<u8>, Option<FileType>); 6] = [
+            ("exec", elf(1, [2, 0]), Some(FileType::Exec)),
+            ("so", elf(1, [3, 0]), Some(FileType::So)),
+            ("so_be", elf(2, [0, 3]), Some(FileType::So)),
+            ("relocatable", elf(1, [1, 0]), Some(FileType::Elf)),
+            ("script", b"#!/bin/sh\necho hello world\n".to_vec(), None),
+            ("short", b"\x7fELF".to_vec(), None)
+        ];
+        for (name, content, expected) in cases {
+            let path = dir.join(name);
+            fs::write(&path, content).unwrap();
+            assert!(detect_file_type(path.to_str().unwrap()) == expected, "{}", name);
+        }
+        assert!(detect_file_type(dir.join("missing").to_str().unwrap()).is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn test_info() -> FileInfo {
+        FileInfo { size: 0, inode: (0, 0), aliases: Vec::new(), track_growth: false, location: None, all_or_nothing: None, list: None }
+    }
+
+    #[test]
+    fn version_key_orders_numerically() {
+        assert_eq!(version_key("1.2.10"), vec![1, 2, 10]);
+        assert!(version_key("1.2.10") > version_key("1.2.9"));
+        assert!(version_key("2.0.1") > version_key("2"));
+        assert!(version_key("10") > version_key("9.9"));
+        assert_eq!(version_key("v3-rc1"), vec![3, 1]);
+        assert!(version_key("").is_empty());
+    }
+
+    #[test]
+    fn keep_latest_versions_by_version() {
+        let paths = ["/lib/libfoo-1.2.9.so", "/lib/libfoo-1.10.0.so", "/lib/libfoo-1.2.10.so", "/lib/libbar-2.so", "/lib/README", "/opt/libfoo-1.0.so", "/lib/libfoo-0.1.so"];
+        // The last one belongs to another list and isn't touched
+        let mut matched: Vec<(usize, String, FileInfo)> = paths.iter().enumerate().map(|(index, path)| (if index == 6 { 0 } else { 1 }, String::from(*path), test_info())).collect();
+        let keep_latest = KeepLatest { count: 1, version: Regex::new(r"\d+(\.\d+)*").unwrap(), by_mtime: false };
+        keep_latest_versions(&mut matched, 1, &keep_latest);
+        let kept: Vec<&str> = matched.iter().map(|file| file.1.as_str()).collect();
+        assert_eq!(kept, ["/lib/libfoo-1.10.0.so", "/lib/libbar-2.so", "/lib/README", "/opt/libfoo-1.0.so", "/lib/libfoo-0.1.so"]);
+
+        let mut matched: Vec<(usize, String, FileInfo)> = paths[..3].iter().map(|path| (1, String::from(*path), test_info())).collect();
+        keep_latest_versions(&mut matched, 1, &KeepLatest { count: 2, ..keep_latest });
+        let kept: Vec<&str> = matched.iter().map(|file| file.1.as_str()).collect();
+        assert_eq!(kept, ["/lib/libfoo-1.10.0.so", "/lib/libfoo-1.2.10.so"]);
+    }
+
+    #[test]
+    fn round_robin_takes_lists_in_turn() {
+        let files: Vec<(String, FileInfo)> = [("a1", Some(2)), ("a2", Some(2)), ("u1", None), ("b1", Some(0)), ("a3", Some(2)), ("u2", None), ("b2", Some(0))].iter().map(|(path, list)| {
+            (String::from(*path), FileInfo { list: *list, ..test_info() })
+        }).collect();
+        // Lists go in the order they first show up, not by their position
+        let ordered: Vec<String> = round_robin(files).into_iter().map(|file| file.0).collect();
+        assert_eq!(ordered, ["a1", "b1", "a2", "b2", "a3", "u1", "u2"]);
+        assert!(round_robin(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn latency_percentiles_nearest_rank() {
+        assert!(latency_percentiles(Vec::new()).is_none());
+        // Unsorted on purpose, mlock times come in the order the threads finish
+        let latencies: Vec<Duration> = (1..=100).rev().map(Duration::from_millis).collect();
+        let latency = latency_percentiles(latencies).unwrap();
+        assert_eq!((latency.p50, latency.p95, latency.p99, latency.max, latency.count),
+            (Duration::from_millis(50), Duration::from_millis(95), Duration::from_millis(99), Duration::from_millis(100), 100));
+        let latency = latency_percentiles(vec![Duration::from_micros(7)]).unwrap();
+        assert_eq!((latency.p50, latency.p99, latency.max, latency.count), (Duration::from_micros(7), Duration::from_micros(7), Duration::from_micros(7), 1));
+        let latency = latency_percentiles([3, 1, 2].into_iter().map(Duration::from_millis).collect()).unwrap();
+        assert_eq!((latency.p50, latency.p95, latency.max), (Duration::from_millis(2), Duration::from_millis(3), Duration::from_millis(3)));
+    }
+
+    #[test]
+    fn parse_json_strings_arrays() {
+        assert_eq!(parse_json_strings("[]"), Ok(Vec::new()));
+        assert_eq!(parse_json_strings(" [ \"libc\\\\.so.*\" ]\n"), Ok(vec![String::from("libc\\.so.*")]));
+        assert_eq!(parse_json_strings("[\"a\",\"b\\\"c\", \"\\/d\\n\"]"), Ok(vec![String::from("a"), String::from("b\"c"), String::from("/d\n")]));
+        assert_eq!(parse_json_strings("[\"\\u00e9\", \"\\ud83d\\ude00\"]"), Ok(vec![String::from("é"), String::from("😀")]));
+    }
+
+    #[test]
+    fn parse_json_strings_malformed() {
+        for json in ["", "{}", "\"a\"", "[", "[\"a\",]", "[\"a\" \"b\"]", "[\"a\"] x", "[] x", "[1]", "[\"a]", "[\"\\x\"]", "[\"\\u12\"]"] {
+            assert!(parse_json_strings(json).is_err(), "{}", json);
+        }
+    }
+
+    fn sized(sizes: &[(u64, Option<usize>)]) -> Vec<(String, FileInfo)> {
+        sizes.iter().enumerate().map(|(index, (size, location))| (format!("f{}", index), FileInfo { size: *size, location: *location, ..test_info() })).collect()
+    }
+
+    fn selected_paths(lock: &Lock, to_load: &[(String, FileInfo)]) -> Vec<String> {
+        Budget::new(lock).select(to_load, lock).into_iter().map(|file| file.0.clone()).collect()
+    }
+
+    #[test]
+    fn budget_select_skips_what_doesnt_fit() {
+        let mut lock = test_lock("budget_select");
+        lock.max_total_size = 1000;
+        let to_load = sized(&[(600, None), (500, None), (300, None), (200, None), (100, None)]);
+        for policy in [BudgetPolicy::Skip, BudgetPolicy::Stop] {
+            lock.budget_policy = policy;
+            assert_eq!(selected_paths(&lock, &to_load), ["f0", "f2", "f4"]);
+        }
+        let mut budget = Budget::new(&lock);
+        budget.select(&to_load, &lock);
+        assert_eq!(budget.total, 1000);
+        // Nothing left for a second pass
+        assert!(budget.select(&to_load, &lock).is_empty());
+    }
+
+    #[test]
+    fn budget_select_location_caps() {
+        let mut lock = test_lock("budget_select_locations");
+        lock.max_total_size = 1000;
+        lock.locations.push(Location { path: String::from("/capped"), max_total_size: Some(500), ..Default::default() });
+        let to_load = sized(&[(400, Some(0)), (200, Some(0)), (300, None), (100, Some(0))]);
+        let mut budget = Budget::new(&lock);
+        let selected: Vec<&str> = budget.select(&to_load, &lock).into_iter().map(|file| file.0.as_str()).collect();
+        assert_eq!(selected, ["f0", "f2", "f3"]);
+        assert_eq!((budget.total, budget.locations[0]), (800, 500));
+    }
+
+    #[test]
+    fn budget_select_whole_pages() {
+        let mut lock = test_lock("budget_select_pages");
+        lock.count_whole_pages = true;
+        lock.max_total_size = 2*page_size();
+        let to_load = sized(&[(1, None), (1, None), (1, None)]);
+        assert_eq!(selected_paths(&lock, &to_load), ["f0", "f1"]);
+    }
+
+    #[test]
+    fn time_of_day_parsing() {
+        assert_eq!(time_of_day("00:00"), Some(0));
+        assert_eq!(time_of_day("23:59"), Some(1439));
+        assert_eq!(time_of_day(" 7:05 "), Some(425));
+        for time in ["24:00", "12:60", "1200", "a:b", "-1:00", "12:", ""] {
+            assert_eq!(time_of_day(time), None, "{}", time);
+        }
+    }
+
+    #[test]
+    fn current_window_around_now() {
+        let mut lock = test_lock("current_window");
+        assert_eq!(current_window(&lock), None);
+        // An hour of margin either way, so the minute turning over during the test doesn't matter
+        let now = local_minutes();
+        let window = |start: u32, end: u32| Window { start: (now+start)%1440, end: (now+end)%1440, lists: Vec::new(), max_total_size: None };
+        lock.schedule = vec![window(60, 120), window(1380, 60)];
+        assert_eq!(current_window(&lock), Some(1));
+        lock.schedule = vec![window(60, 120), window(1320, 1380)];
+        assert_eq!(current_window(&lock), None);
+        // The first matching window wins
+        lock.schedule = vec![window(1380, 60), window(1320, 120)];
+        assert_eq!(current_window(&lock), Some(0));
+    }
+
+    #[test]
+    fn read_peer_state_reads_write_state() {
+        let dir = std::env::temp_dir().join(format!("prelockd-rs-state-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut lock = test_lock("peer_state");
+        lock.allowed_roots = None;
+        let state_file = dir.join("state.json").to_str().unwrap().to_string();
+        lock.state_file = Some(state_file.clone());
+        // Names JSON has to escape, the state of a peer is read back with parse_json_string
+        let paths = [dir.join("locked \"one\""), dir.join("prewarmed"), dir.join("tab\there")];
+        for path in &paths {
+            fs::write(path, b"prelockd").unwrap();
+        }
+        let paths: Vec<String> = paths.iter().map(|path| path.to_str().unwrap().to_string()).collect();
+        {
+            let mut loaded = LOADED.write().unwrap();
+            for (index, path) in paths.iter().enumerate() {
+                let mmap = map_file(path, 8, &lock).unwrap();
+                let aliases = if index == 0 { vec![format!("{}/alias\\", dir.display())] } else { Vec::new() };
+                loaded.push(LoadedFile { path: path.clone(), mmap, locked: index != 1, aliases, track_growth: false, location: None, all_or_nothing: None, relock_attempts: 0 });
+            }
+        }
+        write_state(&lock);
+        LOADED.write().unwrap().retain(|file| !paths.contains(&file.path));
+        // Only locked files count, prewarmed ones can still be evicted
+        assert_eq!(read_peer_state(&state_file), Some(vec![paths[0].clone(), format!("{}/alias\\", dir.display()), paths[2].clone()]));
+        fs::write(&state_file, "{\"version\":1,\"files\":[{\"path\":\"/a\",\"size\":1}]}").unwrap();
+        assert_eq!(read_peer_state(&state_file), None);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(read_peer_state(&state_file), None);
+    }
+}